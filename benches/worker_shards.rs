@@ -0,0 +1,60 @@
+// cargo bench --bench worker_shards
+use bettermq::svc::worker::{TaskItem, Worker};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const THREADS: u64 = 8;
+const PER_THREAD: u64 = 100_000;
+const FETCH_EVERY: u64 = 10;
+const ROUNDS: usize = 5;
+
+// producers that also consume: every thread adds tasks and fetches and finishes
+// a batch every FETCH_EVERY adds, so pushes and fetches meet on the shard locks
+fn run(shards: u32) -> Duration {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let _guard = runtime.enter();
+    let mut worker = Worker::new(shards);
+    let _r = worker.start();
+    let worker = Arc::new(worker);
+    let started = Instant::now();
+    let handles: Vec<_> = (0..THREADS)
+        .map(|t| {
+            let worker = worker.clone();
+            thread::spawn(move || {
+                for i in 0..PER_THREAD {
+                    let id = t * PER_THREAD + i;
+                    worker.add_task(TaskItem {
+                        priority: (id % 10) as i32,
+                        message_id: id.to_be_bytes().to_vec(),
+                        ..Default::default()
+                    });
+                    if i % FETCH_EVERY == FETCH_EVERY - 1 {
+                        let items = worker.fetch_tasks(FETCH_EVERY as u32);
+                        let ids: Vec<Vec<u8>> = items.into_iter().map(|x| x.message_id).collect();
+                        worker.finish_tasks(&ids);
+                    }
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    let elapsed = started.elapsed();
+    runtime.block_on(worker.stop());
+    elapsed
+}
+
+fn main() {
+    for shards in [1, 4] {
+        let mut runs: Vec<Duration> = (0..ROUNDS).map(|_| run(shards)).collect();
+        runs.sort();
+        let median = runs[ROUNDS / 2];
+        let ops = (THREADS * PER_THREAD * 2) as f64 / median.as_secs_f64();
+        println!(
+            "{} shards: median {:?} of {} rounds, {:.0} ops/s",
+            shards, median, ROUNDS, ops
+        );
+    }
+}
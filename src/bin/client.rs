@@ -1,10 +1,15 @@
 use crate::bettermq::priority_queue_client::PriorityQueueClient;
 use crate::bettermq::{
-    AckRequest, CreateTopicRequest, DequeueRequest, EnqueueRequest, GetActiveTopicsRequest,
-    NackRequest, RemoveTopicRequest,
+    AckBatchRequest, AckRequest, BackupTopicRequest, CheckTopicRequest, CreateTopicRequest,
+    DeleteScheduleRequest, DequeueRequest, EnqueueBatchRequest, EnqueueRequest, ExtendLeaseRequest,
+    FetchPayloadRequest, GetActiveTopicsRequest, ListSchedulesRequest, NackBatchRequest,
+    NackRequest, RemoveTopicRequest, RestoreTopicRequest, ScheduleRecurringRequest,
+    SetRateLimitRequest, SubscribeRequest, TopicOptions,
 };
 use clap::{App, Arg, ArgMatches, SubCommand};
 use std::fs;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
 
 pub mod bettermq {
     tonic::include_proto!("bettermq");
@@ -50,6 +55,27 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                         .default_value("0")
                         .value_name("DELIVERY AFTER(ms)"),
                 )
+                .arg(
+                    Arg::with_name("ttl")
+                        .short("e")
+                        .long("ttl")
+                        .default_value("0")
+                        .value_name("EXPIRE AFTER(ms)"),
+                )
+                .arg(
+                    Arg::with_name("group_key")
+                        .short("g")
+                        .long("group")
+                        .default_value("")
+                        .value_name("GROUP KEY"),
+                )
+                .arg(
+                    Arg::with_name("dedup_key")
+                        .short("k")
+                        .long("dedup-key")
+                        .default_value("")
+                        .value_name("DEDUPLICATION KEY"),
+                )
                 .arg(
                     Arg::with_name("priority")
                         .short("r")
@@ -64,6 +90,13 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                         .default_value("10")
                         .value_name("FOR MANY TIMES"),
                 )
+                .arg(
+                    Arg::with_name("batch")
+                        .short("s")
+                        .long("batch")
+                        .default_value("1")
+                        .value_name("BATCH SIZE"),
+                )
                 .arg(
                     Arg::with_name("host")
                         .short("h")
@@ -96,6 +129,45 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                         .default_value("0")
                         .value_name("LEASE(ms)"),
                 )
+                .arg(
+                    Arg::with_name("wait")
+                        .short("w")
+                        .long("wait")
+                        .default_value("0")
+                        .value_name("WAIT TIMEOUT(ms)"),
+                )
+                .arg(
+                    Arg::with_name("host")
+                        .short("h")
+                        .long("host")
+                        .default_value("http://127.0.0.1:8404")
+                        .value_name("HOST ADDRESS"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("subscribe")
+                .about("receive messages pushed from queue")
+                .arg(
+                    Arg::with_name("topic")
+                        .short("t")
+                        .long("topic")
+                        .default_value("root")
+                        .value_name("TOPIC"),
+                )
+                .arg(
+                    Arg::with_name("credits")
+                        .short("c")
+                        .long("credits")
+                        .default_value("1")
+                        .value_name("MAX IN FLIGHT"),
+                )
+                .arg(
+                    Arg::with_name("lease")
+                        .short("l")
+                        .long("lease")
+                        .default_value("10000")
+                        .value_name("LEASE(ms)"),
+                )
                 .arg(
                     Arg::with_name("host")
                         .short("h")
@@ -119,6 +191,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                         .short("i")
                         .long("id")
                         .required(true)
+                        .multiple(true)
                         .value_name("MESSAGE ID"),
                 )
                 .arg(
@@ -129,6 +202,38 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                         .value_name("HOST ADDRESS"),
                 ),
         )
+        .subcommand(
+            SubCommand::with_name("fetch")
+                .about("download the payload of a leased message")
+                .arg(
+                    Arg::with_name("topic")
+                        .short("t")
+                        .long("topic")
+                        .default_value("root")
+                        .value_name("TOPIC"),
+                )
+                .arg(
+                    Arg::with_name("id")
+                        .short("i")
+                        .long("id")
+                        .required(true)
+                        .value_name("MESSAGE ID"),
+                )
+                .arg(
+                    Arg::with_name("file")
+                        .short("f")
+                        .long("file")
+                        .required(true)
+                        .value_name("FILE NAME FOR PAYLOAD"),
+                )
+                .arg(
+                    Arg::with_name("host")
+                        .short("h")
+                        .long("host")
+                        .default_value("http://127.0.0.1:8404")
+                        .value_name("HOST ADDRESS"),
+                ),
+        )
         .subcommand(
             SubCommand::with_name("stats")
                 .about("get statistics of server")
@@ -150,6 +255,104 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                         .default_value("")
                         .value_name("TOPIC"),
                 )
+                .arg(
+                    Arg::with_name("max_deliveries")
+                        .short("d")
+                        .long("max-deliveries")
+                        .default_value("0")
+                        .value_name("MAX DELIVERIES"),
+                )
+                .arg(
+                    Arg::with_name("dead_letter")
+                        .short("l")
+                        .long("dead-letter")
+                        .default_value("")
+                        .value_name("DEAD LETTER TOPIC"),
+                )
+                .arg(
+                    Arg::with_name("ttl")
+                        .short("e")
+                        .long("ttl")
+                        .default_value("0")
+                        .value_name("DEFAULT TTL(ms)"),
+                )
+                .arg(
+                    Arg::with_name("dedup_window")
+                        .short("w")
+                        .long("dedup-window")
+                        .default_value("0")
+                        .value_name("DEDUPLICATION WINDOW(ms)"),
+                )
+                .arg(
+                    Arg::with_name("storage")
+                        .short("s")
+                        .long("storage")
+                        .default_value("")
+                        .value_name("STORAGE ENGINE"),
+                )
+                .arg(
+                    Arg::with_name("compression")
+                        .short("z")
+                        .long("compression")
+                        .default_value("")
+                        .value_name("none|zstd|lz4"),
+                )
+                .arg(
+                    Arg::with_name("compress_min_bytes")
+                        .short("b")
+                        .long("compress-min-bytes")
+                        .default_value("0")
+                        .value_name("COMPRESS THRESHOLD(bytes)"),
+                )
+                .arg(
+                    Arg::with_name("chunk_bytes")
+                        .short("k")
+                        .long("chunk-bytes")
+                        .default_value("0")
+                        .value_name("PAYLOAD CHUNK SIZE(bytes)"),
+                )
+                .arg(
+                    Arg::with_name("durability")
+                        .short("u")
+                        .long("durability")
+                        .default_value("")
+                        .value_name("none|sync|group"),
+                )
+                .arg(
+                    Arg::with_name("group_commit")
+                        .short("g")
+                        .long("group-commit")
+                        .default_value("0")
+                        .value_name("GROUP COMMIT WINDOW(ms)"),
+                )
+                .arg(
+                    Arg::with_name("aging")
+                        .short("a")
+                        .long("aging")
+                        .default_value("0")
+                        .value_name("AGING(ms)"),
+                )
+                .arg(
+                    Arg::with_name("rate")
+                        .short("r")
+                        .long("rate")
+                        .default_value("0")
+                        .value_name("MESSAGES PER SECOND"),
+                )
+                .arg(
+                    Arg::with_name("burst")
+                        .short("n")
+                        .long("burst")
+                        .default_value("0")
+                        .value_name("BURST"),
+                )
+                .arg(
+                    Arg::with_name("shards")
+                        .short("x")
+                        .long("shards")
+                        .default_value("0")
+                        .value_name("WORKER SHARDS"),
+                )
                 .arg(
                     Arg::with_name("host")
                         .short("h")
@@ -176,6 +379,262 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                         .value_name("HOST ADDRESS"),
                 ),
         )
+        .subcommand(
+            SubCommand::with_name("backup")
+                .about("snapshot a topic into a directory on the server")
+                .arg(
+                    Arg::with_name("topic")
+                        .short("t")
+                        .long("topic")
+                        .default_value("root")
+                        .value_name("TOPIC"),
+                )
+                .arg(
+                    Arg::with_name("dir")
+                        .short("d")
+                        .long("dir")
+                        .required(true)
+                        .value_name("BACKUP DIR"),
+                )
+                .arg(
+                    Arg::with_name("host")
+                        .short("h")
+                        .long("host")
+                        .default_value("http://127.0.0.1:8404")
+                        .value_name("HOST ADDRESS"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("restore")
+                .about("create a topic from a backup directory on the server")
+                .arg(
+                    Arg::with_name("topic")
+                        .short("t")
+                        .long("topic")
+                        .required(true)
+                        .value_name("TOPIC"),
+                )
+                .arg(
+                    Arg::with_name("dir")
+                        .short("d")
+                        .long("dir")
+                        .required(true)
+                        .value_name("BACKUP DIR"),
+                )
+                .arg(
+                    Arg::with_name("storage")
+                        .short("s")
+                        .long("storage")
+                        .default_value("")
+                        .value_name("STORAGE"),
+                )
+                .arg(
+                    Arg::with_name("host")
+                        .short("h")
+                        .long("host")
+                        .default_value("http://127.0.0.1:8404")
+                        .value_name("HOST ADDRESS"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("fsck")
+                .about("check the stores of a topic and repair them")
+                .arg(
+                    Arg::with_name("topic")
+                        .short("t")
+                        .long("topic")
+                        .default_value("root")
+                        .value_name("TOPIC"),
+                )
+                .arg(
+                    Arg::with_name("repair")
+                        .short("r")
+                        .long("repair")
+                        .default_value("")
+                        .value_name("reindex|delete|quarantine"),
+                )
+                .arg(
+                    Arg::with_name("quarantine")
+                        .short("q")
+                        .long("quarantine")
+                        .default_value("")
+                        .value_name("QUARANTINE TOPIC"),
+                )
+                .arg(
+                    Arg::with_name("host")
+                        .short("h")
+                        .long("host")
+                        .default_value("http://127.0.0.1:8404")
+                        .value_name("HOST ADDRESS"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("limit")
+                .about("change the delivery rate limit of a topic")
+                .arg(
+                    Arg::with_name("topic")
+                        .short("t")
+                        .long("topic")
+                        .default_value("root")
+                        .value_name("TOPIC"),
+                )
+                .arg(
+                    Arg::with_name("rate")
+                        .short("r")
+                        .long("rate")
+                        .default_value("0")
+                        .value_name("MESSAGES PER SECOND"),
+                )
+                .arg(
+                    Arg::with_name("burst")
+                        .short("n")
+                        .long("burst")
+                        .default_value("0")
+                        .value_name("BURST"),
+                )
+                .arg(
+                    Arg::with_name("host")
+                        .short("h")
+                        .long("host")
+                        .default_value("http://127.0.0.1:8404")
+                        .value_name("HOST ADDRESS"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("extend")
+                .about("extend the lease of a message")
+                .arg(
+                    Arg::with_name("topic")
+                        .short("t")
+                        .long("topic")
+                        .default_value("root")
+                        .value_name("TOPIC"),
+                )
+                .arg(
+                    Arg::with_name("id")
+                        .short("i")
+                        .long("id")
+                        .required(true)
+                        .value_name("MESSAGE ID"),
+                )
+                .arg(
+                    Arg::with_name("lease")
+                        .short("l")
+                        .long("lease")
+                        .required(true)
+                        .value_name("LEASE(ms)"),
+                )
+                .arg(
+                    Arg::with_name("deliveries")
+                        .short("d")
+                        .long("deliveries")
+                        .default_value("0")
+                        .value_name("DELIVERIES"),
+                )
+                .arg(
+                    Arg::with_name("host")
+                        .short("h")
+                        .long("host")
+                        .default_value("http://127.0.0.1:8404")
+                        .value_name("HOST ADDRESS"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("schedule")
+                .about("enqueue a message on a recurring schedule")
+                .arg(
+                    Arg::with_name("topic")
+                        .short("t")
+                        .long("topic")
+                        .default_value("root")
+                        .value_name("TOPIC"),
+                )
+                .arg(
+                    Arg::with_name("cron")
+                        .short("c")
+                        .long("cron")
+                        .default_value("")
+                        .value_name("CRON EXPRESSION"),
+                )
+                .arg(
+                    Arg::with_name("interval")
+                        .short("i")
+                        .long("interval")
+                        .default_value("0")
+                        .value_name("INTERVAL(ms)"),
+                )
+                .arg(
+                    Arg::with_name("meta")
+                        .short("m")
+                        .long("meta")
+                        .default_value("meta")
+                        .value_name("METAINFO"),
+                )
+                .arg(
+                    Arg::with_name("payload")
+                        .short("p")
+                        .long("payload")
+                        .default_value("")
+                        .value_name("MESSAGE DATA"),
+                )
+                .arg(
+                    Arg::with_name("priority")
+                        .short("r")
+                        .long("priority")
+                        .default_value("0")
+                        .value_name("PRIORITY"),
+                )
+                .arg(
+                    Arg::with_name("host")
+                        .short("h")
+                        .long("host")
+                        .default_value("http://127.0.0.1:8404")
+                        .value_name("HOST ADDRESS"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("schedules")
+                .about("list the schedules of a topic")
+                .arg(
+                    Arg::with_name("topic")
+                        .short("t")
+                        .long("topic")
+                        .default_value("root")
+                        .value_name("TOPIC"),
+                )
+                .arg(
+                    Arg::with_name("host")
+                        .short("h")
+                        .long("host")
+                        .default_value("http://127.0.0.1:8404")
+                        .value_name("HOST ADDRESS"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("unschedule")
+                .about("delete a schedule")
+                .arg(
+                    Arg::with_name("topic")
+                        .short("t")
+                        .long("topic")
+                        .default_value("root")
+                        .value_name("TOPIC"),
+                )
+                .arg(
+                    Arg::with_name("id")
+                        .short("i")
+                        .long("id")
+                        .required(true)
+                        .value_name("SCHEDULE ID"),
+                )
+                .arg(
+                    Arg::with_name("host")
+                        .short("h")
+                        .long("host")
+                        .default_value("http://127.0.0.1:8404")
+                        .value_name("HOST ADDRESS"),
+                ),
+        )
         .subcommand(
             SubCommand::with_name("nack")
                 .about("nack a message")
@@ -191,6 +650,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                         .short("i")
                         .long("id")
                         .required(true)
+                        .multiple(true)
                         .value_name("MESSAGE ID"),
                 )
                 .arg(
@@ -223,12 +683,21 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         ("dequeue", Some(subm)) => {
             run_dequeue(subm).await?;
         }
+        ("subscribe", Some(subm)) => {
+            run_subscribe(subm).await?;
+        }
         ("ack", Some(subm)) => {
             run_ack(subm).await?;
         }
+        ("extend", Some(subm)) => {
+            run_extend(subm).await?;
+        }
         ("nack", Some(subm)) => {
             run_nack(subm).await?;
         }
+        ("fetch", Some(subm)) => {
+            run_fetch(subm).await?;
+        }
         ("stats", Some(subm)) => {
             run_stats(subm).await?;
         }
@@ -238,6 +707,27 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         ("remove", Some(subm)) => {
             run_remove(subm).await?;
         }
+        ("backup", Some(subm)) => {
+            run_backup(subm).await?;
+        }
+        ("restore", Some(subm)) => {
+            run_restore(subm).await?;
+        }
+        ("fsck", Some(subm)) => {
+            run_fsck(subm).await?;
+        }
+        ("limit", Some(subm)) => {
+            run_limit(subm).await?;
+        }
+        ("schedule", Some(subm)) => {
+            run_schedule(subm).await?;
+        }
+        ("schedules", Some(subm)) => {
+            run_schedules(subm).await?;
+        }
+        ("unschedule", Some(subm)) => {
+            run_unschedule(subm).await?;
+        }
         _ => {
             return Ok(());
         }
@@ -256,14 +746,33 @@ async fn run_enqueue(opts: &ArgMatches<'_>) -> Result<(), Box<dyn std::error::Er
     if opts.occurrences_of("benchmark") > 0 {
         n = opts.value_of("benchmark").unwrap().parse::<i32>().unwrap();
     }
+    let batch = opts.value_of("batch").unwrap().parse::<i32>().unwrap();
+    let make_request = || EnqueueRequest {
+        topic: opts.value_of("topic").unwrap().into(),
+        payload: payload.clone(),
+        meta: opts.value_of("meta").unwrap().into(),
+        priority: opts.value_of("priority").unwrap().parse::<i32>().unwrap(),
+        deliver_after: opts.value_of("after").unwrap().parse::<u32>().unwrap(),
+        ttl_ms: opts.value_of("ttl").unwrap().parse::<u32>().unwrap(),
+        dedup_key: opts.value_of("dedup_key").unwrap().into(),
+        group_key: opts.value_of("group_key").unwrap().into(),
+    };
+    if batch > 1 {
+        let mut sent = 0;
+        while sent < n {
+            let size = std::cmp::min(batch, n - sent);
+            let request = tonic::Request::new(EnqueueBatchRequest {
+                topic: opts.value_of("topic").unwrap().into(),
+                items: (0..size).map(|_| make_request()).collect(),
+            });
+            let response = client.enqueue_batch(request).await?;
+            println!("{:?}", response);
+            sent += size;
+        }
+        return Ok(());
+    }
     for _i in 0..n {
-        let request = tonic::Request::new(EnqueueRequest {
-            topic: opts.value_of("topic").unwrap().into(),
-            payload: payload.clone(),
-            meta: opts.value_of("meta").unwrap().into(),
-            priority: opts.value_of("priority").unwrap().parse::<i32>().unwrap(),
-            deliver_after: opts.value_of("after").unwrap().parse::<u32>().unwrap(),
-        });
+        let request = tonic::Request::new(make_request());
         let response = client.enqueue(request).await?;
         println!("{:?}", response);
     }
@@ -289,6 +798,7 @@ async fn run_dequeue(opts: &ArgMatches<'_>) -> Result<(), Box<dyn std::error::Er
         topic: opts.value_of("topic").unwrap().into(),
         count: opts.value_of("count").unwrap().parse::<i32>().unwrap(),
         lease_duration: lease,
+        wait_timeout_ms: opts.value_of("wait").unwrap().parse::<u32>().unwrap(),
     });
     let response = client.dequeue(request).await?;
     for item in response.get_ref().items.iter() {
@@ -297,8 +807,42 @@ async fn run_dequeue(opts: &ArgMatches<'_>) -> Result<(), Box<dyn std::error::Er
     Ok(())
 }
 
+async fn run_subscribe(opts: &ArgMatches<'_>) -> Result<(), Box<dyn std::error::Error>> {
+    let mut client = make_conn(opts).await?;
+    let (tx, rx) = mpsc::channel(1);
+    tx.send(SubscribeRequest {
+        topic: opts.value_of("topic").unwrap().into(),
+        lease_duration: opts.value_of("lease").unwrap().parse::<i32>().unwrap(),
+        credits: opts.value_of("credits").unwrap().parse::<u32>().unwrap(),
+    })
+    .await?;
+    let response = client.subscribe(ReceiverStream::new(rx)).await?;
+    let mut inbound = response.into_inner();
+    while let Some(item) = inbound.message().await? {
+        println!("{:?}", item);
+        tx.send(SubscribeRequest {
+            credits: 1,
+            ..Default::default()
+        })
+        .await?;
+    }
+    Ok(())
+}
+
 async fn run_ack(opts: &ArgMatches<'_>) -> Result<(), Box<dyn std::error::Error>> {
     let mut client = make_conn(opts).await?;
+    let message_ids: Vec<String> = opts.values_of("id").unwrap().map(|x| x.into()).collect();
+    if message_ids.len() > 1 {
+        let request = tonic::Request::new(AckBatchRequest {
+            message_ids,
+            topic: opts.value_of("topic").unwrap().into(),
+        });
+        let response = client.ack_batch(request).await?;
+        for result in &response.get_ref().results {
+            println!("{:?}", result);
+        }
+        return Ok(());
+    }
     let message_id = opts.value_of("id").unwrap().into();
     let request = tonic::Request::new(AckRequest {
         message_id: message_id,
@@ -309,8 +853,51 @@ async fn run_ack(opts: &ArgMatches<'_>) -> Result<(), Box<dyn std::error::Error>
     Ok(())
 }
 
+async fn run_extend(opts: &ArgMatches<'_>) -> Result<(), Box<dyn std::error::Error>> {
+    let mut client = make_conn(opts).await?;
+    let request = tonic::Request::new(ExtendLeaseRequest {
+        topic: opts.value_of("topic").unwrap().into(),
+        message_id: opts.value_of("id").unwrap().into(),
+        lease_duration: opts.value_of("lease").unwrap().parse::<i32>().unwrap(),
+        deliveries: opts.value_of("deliveries").unwrap().parse::<u32>().unwrap(),
+    });
+    let response = client.extend_lease(request).await?;
+    println!("{:?}", response);
+    Ok(())
+}
+
+async fn run_fetch(opts: &ArgMatches<'_>) -> Result<(), Box<dyn std::error::Error>> {
+    let mut client = make_conn(opts).await?;
+    let request = tonic::Request::new(FetchPayloadRequest {
+        topic: opts.value_of("topic").unwrap().into(),
+        message_id: opts.value_of("id").unwrap().into(),
+    });
+    let mut inbound = client.fetch_payload(request).await?.into_inner();
+    let mut payload = Vec::<u8>::new();
+    while let Some(chunk) = inbound.message().await? {
+        payload.extend_from_slice(&chunk.data);
+        println!("chunk {}/{}", chunk.index + 1, chunk.total);
+    }
+    fs::write(opts.value_of("file").unwrap(), payload)?;
+    Ok(())
+}
+
 async fn run_nack(opts: &ArgMatches<'_>) -> Result<(), Box<dyn std::error::Error>> {
     let mut client = make_conn(opts).await?;
+    let message_ids: Vec<String> = opts.values_of("id").unwrap().map(|x| x.into()).collect();
+    if message_ids.len() > 1 {
+        let request = tonic::Request::new(NackBatchRequest {
+            message_ids,
+            topic: opts.value_of("topic").unwrap().into(),
+            meta: opts.value_of("meta").unwrap().into(),
+            deliver_after: opts.value_of("after").unwrap().parse::<u32>().unwrap(),
+        });
+        let response = client.nack_batch(request).await?;
+        for result in &response.get_ref().results {
+            println!("{:?}", result);
+        }
+        return Ok(());
+    }
     let message_id = opts.value_of("id").unwrap().into();
     let request = tonic::Request::new(NackRequest {
         message_id: message_id,
@@ -335,8 +922,45 @@ async fn run_stats(opts: &ArgMatches<'_>) -> Result<(), Box<dyn std::error::Erro
 
 async fn run_create(opts: &ArgMatches<'_>) -> Result<(), Box<dyn std::error::Error>> {
     let mut client = make_conn(opts).await?;
+    let options = TopicOptions {
+        max_deliveries: opts
+            .value_of("max_deliveries")
+            .unwrap()
+            .parse::<u32>()
+            .unwrap(),
+        dead_letter_topic: opts.value_of("dead_letter").unwrap().into(),
+        default_ttl_ms: opts.value_of("ttl").unwrap().parse::<u32>().unwrap(),
+        dedup_window_ms: opts
+            .value_of("dedup_window")
+            .unwrap()
+            .parse::<u32>()
+            .unwrap(),
+        storage: opts.value_of("storage").unwrap().into(),
+        compression: opts.value_of("compression").unwrap().into(),
+        compress_min_bytes: opts
+            .value_of("compress_min_bytes")
+            .unwrap()
+            .parse::<u32>()
+            .unwrap(),
+        chunk_bytes: opts
+            .value_of("chunk_bytes")
+            .unwrap()
+            .parse::<u32>()
+            .unwrap(),
+        durability: opts.value_of("durability").unwrap().into(),
+        group_commit_ms: opts
+            .value_of("group_commit")
+            .unwrap()
+            .parse::<u32>()
+            .unwrap(),
+        aging_ms: opts.value_of("aging").unwrap().parse::<u32>().unwrap(),
+        rate_limit: opts.value_of("rate").unwrap().parse::<u32>().unwrap(),
+        rate_burst: opts.value_of("burst").unwrap().parse::<u32>().unwrap(),
+        worker_shards: opts.value_of("shards").unwrap().parse::<u32>().unwrap(),
+    };
     let request = tonic::Request::new(CreateTopicRequest {
         topic: opts.value_of("topic").unwrap().into(),
+        options: Some(options),
     });
     let response = client.create_topic(request).await?;
     println!("{:?}", response);
@@ -352,3 +976,93 @@ async fn run_remove(opts: &ArgMatches<'_>) -> Result<(), Box<dyn std::error::Err
     println!("{:?}", response);
     Ok(())
 }
+
+async fn run_backup(opts: &ArgMatches<'_>) -> Result<(), Box<dyn std::error::Error>> {
+    let mut client = make_conn(opts).await?;
+    let request = tonic::Request::new(BackupTopicRequest {
+        topic: opts.value_of("topic").unwrap().into(),
+        dir: opts.value_of("dir").unwrap().into(),
+    });
+    let response = client.backup_topic(request).await?;
+    println!("{:?}", response);
+    Ok(())
+}
+
+async fn run_restore(opts: &ArgMatches<'_>) -> Result<(), Box<dyn std::error::Error>> {
+    let mut client = make_conn(opts).await?;
+    let request = tonic::Request::new(RestoreTopicRequest {
+        topic: opts.value_of("topic").unwrap().into(),
+        dir: opts.value_of("dir").unwrap().into(),
+        storage: opts.value_of("storage").unwrap().into(),
+    });
+    let response = client.restore_topic(request).await?;
+    println!("{:?}", response);
+    Ok(())
+}
+
+async fn run_fsck(opts: &ArgMatches<'_>) -> Result<(), Box<dyn std::error::Error>> {
+    let mut client = make_conn(opts).await?;
+    let request = tonic::Request::new(CheckTopicRequest {
+        topic: opts.value_of("topic").unwrap().into(),
+        repair: opts.value_of("repair").unwrap().into(),
+        quarantine_topic: opts.value_of("quarantine").unwrap().into(),
+    });
+    let response = client.check_topic(request).await?;
+    println!("{:?}", response);
+    Ok(())
+}
+
+async fn run_limit(opts: &ArgMatches<'_>) -> Result<(), Box<dyn std::error::Error>> {
+    let mut client = make_conn(opts).await?;
+    let request = tonic::Request::new(SetRateLimitRequest {
+        topic: opts.value_of("topic").unwrap().into(),
+        rate_limit: opts.value_of("rate").unwrap().parse::<u32>().unwrap(),
+        rate_burst: opts.value_of("burst").unwrap().parse::<u32>().unwrap(),
+    });
+    let response = client.set_rate_limit(request).await?;
+    println!("{:?}", response);
+    Ok(())
+}
+
+async fn run_schedule(opts: &ArgMatches<'_>) -> Result<(), Box<dyn std::error::Error>> {
+    let mut client = make_conn(opts).await?;
+    let template = EnqueueRequest {
+        topic: opts.value_of("topic").unwrap().into(),
+        payload: opts.value_of("payload").unwrap().as_bytes().to_vec(),
+        meta: opts.value_of("meta").unwrap().into(),
+        priority: opts.value_of("priority").unwrap().parse::<i32>().unwrap(),
+        ..Default::default()
+    };
+    let request = tonic::Request::new(ScheduleRecurringRequest {
+        topic: opts.value_of("topic").unwrap().into(),
+        cron: opts.value_of("cron").unwrap().into(),
+        interval_ms: opts.value_of("interval").unwrap().parse::<u32>().unwrap(),
+        template: Some(template),
+    });
+    let response = client.schedule_recurring(request).await?;
+    println!("{:?}", response);
+    Ok(())
+}
+
+async fn run_schedules(opts: &ArgMatches<'_>) -> Result<(), Box<dyn std::error::Error>> {
+    let mut client = make_conn(opts).await?;
+    let request = tonic::Request::new(ListSchedulesRequest {
+        topic: opts.value_of("topic").unwrap().into(),
+    });
+    let response = client.list_schedules(request).await?;
+    for schedule in &response.get_ref().schedules {
+        println!("{:?}", schedule);
+    }
+    Ok(())
+}
+
+async fn run_unschedule(opts: &ArgMatches<'_>) -> Result<(), Box<dyn std::error::Error>> {
+    let mut client = make_conn(opts).await?;
+    let request = tonic::Request::new(DeleteScheduleRequest {
+        topic: opts.value_of("topic").unwrap().into(),
+        schedule_id: opts.value_of("id").unwrap().into(),
+    });
+    let response = client.delete_schedule(request).await?;
+    println!("{:?}", response);
+    Ok(())
+}
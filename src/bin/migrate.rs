@@ -0,0 +1,45 @@
+use bettermq::storage::kv::DbKind;
+use bettermq::svc;
+use clap::{App, Arg};
+use std::str::FromStr;
+use tracing_subscriber;
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let opts = App::new("bmq-migrate")
+        .about("move a topic to another storage engine, the server must be stopped")
+        .arg(
+            Arg::with_name("data_dir")
+                .short("d")
+                .long("data-dir")
+                .default_value("/tmp/demo_queue")
+                .value_name("DATA DIR"),
+        )
+        .arg(
+            Arg::with_name("topic")
+                .short("t")
+                .long("topic")
+                .required(true)
+                .value_name("TOPIC"),
+        )
+        .arg(
+            Arg::with_name("storage")
+                .short("s")
+                .long("storage")
+                .required(true)
+                .value_name("sled|rocksdb|rocksdb_cf"),
+        )
+        .get_matches();
+    tracing_subscriber::fmt().init();
+    let storage = opts.value_of("storage").unwrap();
+    let db_kind = match DbKind::from_str(storage) {
+        Ok(db_kind) => db_kind,
+        Err(_) => return Err(format!("unknown storage: {}", storage).into()),
+    };
+    let data_dir = opts.value_of("data_dir").unwrap();
+    let topic = opts.value_of("topic").unwrap();
+    match svc::multi_queue::migrate_topic(data_dir, topic, db_kind) {
+        Ok(total) => println!("moved {} keys of {} to {}", total, topic, db_kind),
+        Err(err) => return Err(format!("migrate {} failed: {}", topic, err).into()),
+    }
+    Ok(())
+}
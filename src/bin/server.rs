@@ -1,7 +1,10 @@
+use bettermq::storage::kv::DbKind;
 use bettermq::svc;
 use clap::{app_from_crate, crate_authors, crate_description, crate_name, crate_version};
 use serde_derive::Deserialize;
+use std::collections::HashMap;
 use std::fs;
+use std::str::FromStr;
 use tokio::time;
 use tokio::time::Duration;
 use tonic::transport::Server;
@@ -26,7 +29,23 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .init();
     let addr = cfg.listen_grpc.parse()?;
     let root_dir = cfg.data_dir.clone();
-    let svr = svc::multi_queue::new(cfg.data_dir, cfg.node_id, cfg.topics);
+    let db_kind = match DbKind::from_str(&cfg.storage) {
+        Ok(db_kind) => db_kind,
+        Err(_) => return Err(format!("unknown storage: {}", cfg.storage).into()),
+    };
+    for (topic, storage) in cfg.topic_storage.iter() {
+        if DbKind::from_str(storage).is_err() {
+            return Err(format!("unknown storage of {}: {}", topic, storage).into());
+        }
+    }
+    let svr = svc::multi_queue::new(
+        cfg.data_dir,
+        cfg.node_id,
+        cfg.topics,
+        db_kind,
+        cfg.topic_storage,
+        cfg.backup_dir,
+    );
     tokio::task::spawn(async move { clean_garbage(&root_dir).await });
     info!("happy start");
     Server::builder().add_service(svr).serve(addr).await?;
@@ -38,8 +57,12 @@ struct Config {
     node_id: String,
     listen_grpc: String,
     data_dir: String,
+    backup_dir: String,
     log_level: String,
     topics: Vec<String>,
+    storage: String,
+    #[serde(default)]
+    topic_storage: HashMap<String, String>,
 }
 
 impl Config {
@@ -48,8 +71,10 @@ impl Config {
         c.set_default("node_id", "1")?;
         c.set_default("listen_grpc", "127.0.0.1:8402")?;
         c.set_default("data_dir", "/tmp/demo_queue")?;
+        c.set_default("backup_dir", "/tmp/demo_queue_backup")?;
         c.set_default("log_level", "info")?;
         c.set_default("topics", vec!["root"])?;
+        c.set_default("storage", "rocksdb")?;
         c.merge(config::File::with_name(file))?;
         c.merge(config::Environment::with_prefix("BETTERMQ"))?;
         Ok(c.try_into()?)
@@ -1,50 +1,281 @@
-use std::cmp::Ordering;
-use strum_macros::Display;
+use sled::transaction::ConflictableTransactionResult;
+use sled::Transactional;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fs;
+use std::io;
+use std::ops::Bound;
+use std::sync::{Arc, Mutex, RwLock, Weak};
+use std::thread;
+use std::time::Duration;
+use strum_macros::{Display, EnumString};
 use tracing::info;
 
+#[derive(Clone, Copy, Debug, PartialEq, Display, EnumString)]
+#[strum(serialize_all = "lowercase", ascii_case_insensitive)]
 pub enum DbKind {
     SLED,
     ROCKSDB,
+    // a column family of a rocksdb shared by every store under the same parent dir
+    #[strum(serialize = "rocksdb_cf")]
+    ROCKSDBCF,
+    // not durable, the data is gone when the last handle drops
+    MEMORY,
 }
 
+const MEMORY_ITER_PAGE: usize = 256;
+const SLED_OPEN_RETRIES: u32 = 50;
+const SLED_OPEN_RETRY_MS: u64 = 10;
+
+type RocksDB = rocksdb::DBWithThreadMode<rocksdb::MultiThreaded>;
+
+// shared databases of ROCKSDBCF stores, closed when the last handle drops
+static SHARED_DBS: Mutex<Vec<(String, Weak<RocksDB>)>> = Mutex::new(Vec::new());
+
 #[derive(Debug, Display)]
 pub enum KvError {
     NotFound(String),
     IoError(String),
 }
 
+pub enum BatchOp {
+    Put(Vec<u8>, Vec<u8>),
+    Delete(Vec<u8>),
+}
+
+// puts and deletes over the spaces of one database, applied all or nothing
+#[derive(Default)]
+pub struct WriteBatch {
+    ops: Vec<(String, BatchOp)>,
+    sync: bool,
+}
+
+impl WriteBatch {
+    pub fn put(&mut self, store: &dyn KvStore, key: Vec<u8>, value: Vec<u8>) {
+        self.ops
+            .push((store.space().into(), BatchOp::Put(key, value)));
+    }
+
+    pub fn delete(&mut self, store: &dyn KvStore, key: Vec<u8>) {
+        self.ops.push((store.space().into(), BatchOp::Delete(key)));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    pub fn append(&mut self, other: WriteBatch) {
+        self.ops.extend(other.ops);
+        self.sync |= other.sync;
+    }
+
+    // a synced batch is on disk when write returns
+    pub fn set_sync(&mut self, sync: bool) {
+        self.sync = sync;
+    }
+
+    pub fn is_sync(&self) -> bool {
+        self.sync
+    }
+
+    // ops grouped by space, in first seen order
+    fn by_space(self) -> Vec<(String, Vec<BatchOp>)> {
+        let mut spaces = Vec::<(String, Vec<BatchOp>)>::new();
+        for (space, op) in self.ops {
+            match spaces.iter_mut().find(|(name, _)| *name == space) {
+                Some((_, ops)) => ops.push(op),
+                None => spaces.push((space, vec![op])),
+            }
+        }
+        spaces
+    }
+}
+
+pub type KvIter<'a> = Box<dyn Iterator<Item = Result<(Vec<u8>, Vec<u8>), KvError>> + 'a>;
+
+// keys between two bounds, visited in ascending order unless reversed
+#[derive(Clone, Debug)]
+pub struct KeyRange {
+    start: Bound<Vec<u8>>,
+    end: Bound<Vec<u8>>,
+    reverse: bool,
+}
+
+impl KeyRange {
+    pub fn all() -> Self {
+        KeyRange {
+            start: Bound::Unbounded,
+            end: Bound::Unbounded,
+            reverse: false,
+        }
+    }
+
+    // start included, end excluded
+    pub fn between(start: Vec<u8>, end: Vec<u8>) -> Self {
+        KeyRange {
+            start: Bound::Included(start),
+            end: Bound::Excluded(end),
+            reverse: false,
+        }
+    }
+
+    // keys after the given one, to resume an earlier iteration
+    pub fn after(key: Vec<u8>) -> Self {
+        KeyRange {
+            start: Bound::Excluded(key),
+            end: Bound::Unbounded,
+            reverse: false,
+        }
+    }
+
+    pub fn prefix(prefix: &[u8]) -> Self {
+        let mut end = prefix.to_vec();
+        while let Some(last) = end.pop() {
+            if last < 255 {
+                end.push(last + 1);
+                break;
+            }
+        }
+        KeyRange {
+            start: Bound::Included(prefix.to_vec()),
+            end: match end.is_empty() {
+                true => Bound::Unbounded,
+                false => Bound::Excluded(end),
+            },
+            reverse: false,
+        }
+    }
+
+    pub fn rev(mut self) -> Self {
+        self.reverse = !self.reverse;
+        self
+    }
+
+    fn after_start(&self, key: &[u8]) -> bool {
+        match &self.start {
+            Bound::Included(start) => key >= start.as_slice(),
+            Bound::Excluded(start) => key > start.as_slice(),
+            Bound::Unbounded => true,
+        }
+    }
+
+    fn before_end(&self, key: &[u8]) -> bool {
+        match &self.end {
+            Bound::Included(end) => key <= end.as_slice(),
+            Bound::Excluded(end) => key < end.as_slice(),
+            Bound::Unbounded => true,
+        }
+    }
+
+    // btree ranges panic on inverted bounds, so those are caught first
+    fn is_empty(&self) -> bool {
+        match (&self.start, &self.end) {
+            (Bound::Included(start), Bound::Included(end)) => start > end,
+            (Bound::Included(start), Bound::Excluded(end))
+            | (Bound::Excluded(start), Bound::Included(end))
+            | (Bound::Excluded(start), Bound::Excluded(end)) => start >= end,
+            _ => false,
+        }
+    }
+
+    fn bounds(&self) -> (Bound<Vec<u8>>, Bound<Vec<u8>>) {
+        (self.start.clone(), self.end.clone())
+    }
+}
+
 pub trait KvStore: Send + Sync + 'static {
     fn get(&self, key: &Vec<u8>) -> Result<Vec<u8>, KvError>;
     fn set(&self, key: &Vec<u8>, value: Vec<u8>) -> Result<(), KvError>;
-    fn scan(
-        &self,
-        start: &Vec<u8>,
-        end: &Vec<u8>,
-        limit: u32,
-        items: &mut Vec<(Vec<u8>, Vec<u8>)>,
-    ) -> Result<(), KvError>;
+    fn write(&self, batch: WriteBatch) -> Result<(), KvError>;
+    fn iter(&self, range: KeyRange) -> Result<KvIter<'_>, KvError>;
     fn remove(&self, key: &Vec<u8>) -> Result<(), KvError>;
     fn max_key(&self) -> Result<Vec<u8>, KvError>;
+    // "" is the default space of the database
+    fn space(&self) -> &str;
+    fn open_space(&self, name: &str) -> Result<Box<dyn KvStore>, KvError>;
+    // copies the whole database into a new one at dir and returns its engine,
+    // None when this store shares its database and can not be copied alone
+    fn checkpoint(&self, dir: &str) -> Result<Option<DbKind>, KvError>;
 }
 
 #[derive(Debug)]
 struct SledKv {
     db: sled::Db,
+    tree: sled::Tree,
+    space: String,
+}
+
+type MemorySpaces = HashMap<String, BTreeMap<Vec<u8>, Vec<u8>>>;
+
+#[derive(Debug)]
+struct MemoryKv {
+    spaces: Arc<RwLock<MemorySpaces>>,
+    space: String,
+}
+
+// copies a page at a time under the read lock, like the disk engines it sees
+// writes made after it started and is no snapshot
+struct MemoryIter<'a> {
+    kv: &'a MemoryKv,
+    // what is left to visit, narrowed past every page taken
+    range: KeyRange,
+    page: VecDeque<(Vec<u8>, Vec<u8>)>,
+}
+
+impl MemoryIter<'_> {
+    fn next_page(&mut self) {
+        if self.range.is_empty() {
+            return;
+        }
+        let spaces = self.kv.spaces.read().unwrap();
+        let map = match spaces.get(&self.kv.space) {
+            Some(map) => map,
+            None => return,
+        };
+        let items = map.range::<Vec<u8>, _>(self.range.bounds());
+        let clone = |(key, value): (&Vec<u8>, &Vec<u8>)| (key.clone(), value.clone());
+        self.page = match self.range.reverse {
+            true => items.rev().take(MEMORY_ITER_PAGE).map(clone).collect(),
+            false => items.take(MEMORY_ITER_PAGE).map(clone).collect(),
+        };
+        if let Some((last, _)) = self.page.back() {
+            match self.range.reverse {
+                true => self.range.end = Bound::Excluded(last.clone()),
+                false => self.range.start = Bound::Excluded(last.clone()),
+            }
+        }
+    }
+}
+
+impl Iterator for MemoryIter<'_> {
+    type Item = Result<(Vec<u8>, Vec<u8>), KvError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.page.is_empty() {
+            self.next_page();
+        }
+        self.page.pop_front().map(Ok)
+    }
 }
 
 #[derive(Debug)]
 struct RocksDBKv {
-    db: rocksdb::DBWithThreadMode<rocksdb::MultiThreaded>,
+    db: Arc<RocksDB>,
+    space: String,
 }
 
 pub fn new_kvstore(dbkind: DbKind, dir: String) -> Result<Box<dyn KvStore>, KvError> {
     match dbkind {
         DbKind::SLED => {
             info!("open db: {:}", dir);
-            let db = sled::open(dir);
+            let db = open_sled(&dir);
             match db {
                 Ok(idb) => {
-                    let kvs = Box::new(SledKv { db: idb });
+                    let tree = (*idb).clone();
+                    let kvs = Box::new(SledKv {
+                        db: idb,
+                        tree,
+                        space: "".into(),
+                    });
                     Ok(kvs)
                 }
                 Err(err) => Err(KvError::IoError(err.to_string())),
@@ -52,23 +283,168 @@ pub fn new_kvstore(dbkind: DbKind, dir: String) -> Result<Box<dyn KvStore>, KvEr
         }
         DbKind::ROCKSDB => {
             info!("open db (leveldb) {:}", dir);
-            let mut opts = rocksdb::Options::default();
-            opts.create_if_missing(true);
-            let db = rocksdb::DBWithThreadMode::<rocksdb::MultiThreaded>::open(&opts, dir);
-            match db {
-                Ok(db) => {
-                    let kvs = Box::new(RocksDBKv { db: db });
-                    Ok(kvs)
+            let kvs = Box::new(RocksDBKv {
+                db: Arc::new(open_rocksdb(&dir)?),
+                space: "".into(),
+            });
+            Ok(kvs)
+        }
+        DbKind::ROCKSDBCF => {
+            let (db_dir, cf) = split_cf_dir(&dir)?;
+            info!("open column family {:} in {:}", cf, db_dir);
+            let kvs = RocksDBKv {
+                db: shared_rocksdb(db_dir)?,
+                space: "".into(),
+            };
+            kvs.open_space(cf)
+        }
+        DbKind::MEMORY => {
+            info!("open db (memory) {:}", dir);
+            let kvs = Box::new(MemoryKv {
+                spaces: Arc::new(RwLock::new(HashMap::new())),
+                space: "".into(),
+            });
+            Ok(kvs)
+        }
+    }
+}
+
+// a dropped directory is renamed with a _gc suffix and removed by the garbage collector
+// a dropped column family is gone at once, every store opened on it has to be dropped first
+// a dropped sled db lets go of its file lock from a background thread, a moment later
+fn open_sled(dir: &str) -> sled::Result<sled::Db> {
+    let mut retries = SLED_OPEN_RETRIES;
+    loop {
+        match sled::open(dir) {
+            Err(sled::Error::Io(err)) if err.kind() == io::ErrorKind::WouldBlock && retries > 0 => {
+                retries -= 1;
+                thread::sleep(Duration::from_millis(SLED_OPEN_RETRY_MS));
+            }
+            db => return db,
+        }
+    }
+}
+
+pub fn drop_kvstore(dbkind: DbKind, dir: String) -> Result<(), KvError> {
+    match dbkind {
+        DbKind::ROCKSDBCF => {
+            let (db_dir, cf) = split_cf_dir(&dir)?;
+            let db = shared_rocksdb(db_dir)?;
+            let sub_prefix = format!("{}/", cf);
+            for name in RocksDB::list_cf(&rocksdb::Options::default(), db_dir).unwrap_or_default() {
+                if name != cf && !name.starts_with(&sub_prefix) {
+                    continue;
                 }
-                Err(err) => Err(KvError::IoError(err.to_string())),
+                info!("drop column family {:} in {:}", name, db_dir);
+                if let Err(err) = db.drop_cf(&name) {
+                    return Err(KvError::IoError(err.to_string()));
+                }
+            }
+            Ok(())
+        }
+        DbKind::MEMORY => Ok(()),
+        _ => match fs::rename(&dir, format!("{}_gc", dir)) {
+            Ok(_) => Ok(()),
+            Err(err) => Err(KvError::IoError(err.to_string())),
+        },
+    }
+}
+
+// top level column families of a shared database, sub spaces are skipped
+pub fn list_column_families(db_dir: &str) -> Vec<String> {
+    let names = RocksDB::list_cf(&rocksdb::Options::default(), db_dir).unwrap_or_default();
+    let mut names: Vec<String> = names
+        .into_iter()
+        .filter(|name| name != "default" && !name.contains('/'))
+        .collect();
+    names.sort();
+    names
+}
+
+fn split_cf_dir(dir: &str) -> Result<(&str, &str), KvError> {
+    match dir.rsplit_once('/') {
+        Some((db_dir, cf)) if !db_dir.is_empty() && !cf.is_empty() => Ok((db_dir, cf)),
+        _ => Err(KvError::IoError(format!(
+            "invalid column family path {}",
+            dir
+        ))),
+    }
+}
+
+fn space_name(parent: &str, name: &str) -> String {
+    if parent.is_empty() {
+        return name.into();
+    }
+    format!("{}/{}", parent, name)
+}
+
+fn open_rocksdb(dir: &str) -> Result<RocksDB, KvError> {
+    let mut opts = rocksdb::Options::default();
+    opts.create_if_missing(true);
+    opts.create_missing_column_families(true);
+    let spaces = RocksDB::list_cf(&opts, dir).unwrap_or_default();
+    match RocksDB::open_cf(&opts, dir, spaces) {
+        Ok(db) => Ok(db),
+        Err(err) => Err(KvError::IoError(err.to_string())),
+    }
+}
+
+fn shared_rocksdb(db_dir: &str) -> Result<Arc<RocksDB>, KvError> {
+    let mut shared_dbs = SHARED_DBS.lock().unwrap();
+    shared_dbs.retain(|(_, db)| db.strong_count() > 0);
+    for (dir, db) in shared_dbs.iter() {
+        if dir == db_dir {
+            if let Some(db) = db.upgrade() {
+                return Ok(db);
             }
         }
     }
+    info!("open shared db (leveldb) {:}", db_dir);
+    let db = Arc::new(open_rocksdb(db_dir)?);
+    shared_dbs.push((db_dir.into(), Arc::downgrade(&db)));
+    Ok(db)
+}
+
+// copies every key of src into dst, returns the number of keys copied
+pub fn copy_all(src: &dyn KvStore, dst: &dyn KvStore) -> Result<u64, KvError> {
+    let mut total = 0;
+    let mut batch = WriteBatch::default();
+    for item in src.iter(KeyRange::all())? {
+        let (key, value) = item?;
+        batch.put(dst, key, value);
+        total += 1;
+        if total % 100 == 0 {
+            dst.write(std::mem::take(&mut batch))?;
+        }
+    }
+    if !batch.is_empty() {
+        dst.write(batch)?;
+    }
+    Ok(total)
+}
+
+// trims a seeked iterator to the range, a seek may land on an excluded bound
+fn bounded<'a, I>(it: I, range: KeyRange) -> KvIter<'a>
+where
+    I: Iterator<Item = (Vec<u8>, Vec<u8>)> + 'a,
+{
+    let skip = range.clone();
+    Box::new(
+        it.skip_while(move |(key, _)| match skip.reverse {
+            true => !skip.before_end(key),
+            false => !skip.after_start(key),
+        })
+        .take_while(move |(key, _)| match range.reverse {
+            true => range.after_start(key),
+            false => range.before_end(key),
+        })
+        .map(Ok),
+    )
 }
 
 impl KvStore for SledKv {
     fn get(&self, key: &Vec<u8>) -> Result<Vec<u8>, KvError> {
-        match self.db.get(key) {
+        match self.tree.get(key) {
             Ok(value) => match value {
                 Some(value) => Ok(value.to_vec()),
                 None => Err(KvError::NotFound("key not found".into())),
@@ -77,42 +453,71 @@ impl KvStore for SledKv {
         }
     }
     fn set(&self, key: &Vec<u8>, value: Vec<u8>) -> Result<(), KvError> {
-        let r = self.db.insert(key, value);
+        let r = self.tree.insert(key, value);
         match r {
             Ok(_) => Ok(()),
             Err(err) => Err(KvError::IoError(err.to_string())),
         }
     }
 
-    fn scan(
-        &self,
-        start: &Vec<u8>,
-        end: &Vec<u8>,
-        limit: u32,
-        items: &mut Vec<(Vec<u8>, Vec<u8>)>,
-    ) -> Result<(), KvError> {
-        let start = start.as_slice();
-        let end = end.as_slice();
-        let mut ct = 0 as u32;
-        for sr in self.db.range(start..end) {
-            match sr {
-                Ok((key, value)) => {
-                    items.push((key.to_vec(), value.to_vec()));
-                    ct += 1;
-                    if ct >= limit {
-                        break;
-                    }
-                }
-                Err(err) => {
-                    return Err(KvError::IoError(err.to_string()));
+    fn write(&self, batch: WriteBatch) -> Result<(), KvError> {
+        let sync = batch.sync;
+        let mut trees = Vec::<sled::Tree>::new();
+        let mut batches = Vec::<sled::Batch>::new();
+        for (space, ops) in batch.by_space() {
+            let tree = match space.as_str() {
+                "" => (*self.db).clone(),
+                space => match self.db.open_tree(space) {
+                    Ok(tree) => tree,
+                    Err(err) => return Err(KvError::IoError(err.to_string())),
+                },
+            };
+            let mut batch = sled::Batch::default();
+            for op in ops {
+                match op {
+                    BatchOp::Put(key, value) => batch.insert(key, value),
+                    BatchOp::Delete(key) => batch.remove(key),
                 }
             }
+            trees.push(tree);
+            batches.push(batch);
+        }
+        let r = trees[..].transaction(|views| -> ConflictableTransactionResult<(), ()> {
+            for (view, batch) in views.iter().zip(batches.iter()) {
+                view.apply_batch(batch)?;
+            }
+            Ok(())
+        });
+        if let Err(err) = r {
+            return Err(KvError::IoError(format!("{:?}", err)));
+        }
+        if sync {
+            if let Err(err) = self.db.flush() {
+                return Err(KvError::IoError(err.to_string()));
+            }
         }
         Ok(())
     }
 
+    fn iter(&self, range: KeyRange) -> Result<KvIter<'_>, KvError> {
+        if range.is_empty() {
+            return Ok(Box::new(std::iter::empty()));
+        }
+        let it = self
+            .tree
+            .range::<Vec<u8>, _>(range.bounds())
+            .map(|item| match item {
+                Ok((key, value)) => Ok((key.to_vec(), value.to_vec())),
+                Err(err) => Err(KvError::IoError(err.to_string())),
+            });
+        match range.reverse {
+            true => Ok(Box::new(it.rev())),
+            false => Ok(Box::new(it)),
+        }
+    }
+
     fn remove(&self, key: &Vec<u8>) -> Result<(), KvError> {
-        let r = self.db.remove(key);
+        let r = self.tree.remove(key);
         match r {
             Ok(_) => Ok(()),
             Err(err) => Err(KvError::IoError(err.to_string())),
@@ -120,7 +525,7 @@ impl KvStore for SledKv {
     }
 
     fn max_key(&self) -> Result<Vec<u8>, KvError> {
-        let last = self.db.last();
+        let last = self.tree.last();
         match last {
             Ok(last) => match last {
                 Some((key, _)) => Ok(key.to_vec()),
@@ -129,11 +534,138 @@ impl KvStore for SledKv {
             Err(err) => Err(KvError::IoError(err.to_string())),
         }
     }
+
+    fn space(&self) -> &str {
+        &self.space
+    }
+
+    fn open_space(&self, name: &str) -> Result<Box<dyn KvStore>, KvError> {
+        let space = space_name(&self.space, name);
+        match self.db.open_tree(&space) {
+            Ok(tree) => Ok(Box::new(SledKv {
+                db: self.db.clone(),
+                tree,
+                space,
+            })),
+            Err(err) => Err(KvError::IoError(err.to_string())),
+        }
+    }
+
+    fn checkpoint(&self, dir: &str) -> Result<Option<DbKind>, KvError> {
+        let copy = match sled::open(dir) {
+            Ok(copy) => copy,
+            Err(err) => return Err(KvError::IoError(err.to_string())),
+        };
+        copy.import(self.db.export());
+        match copy.flush() {
+            Ok(_) => Ok(Some(DbKind::SLED)),
+            Err(err) => Err(KvError::IoError(err.to_string())),
+        }
+    }
+}
+
+impl KvStore for MemoryKv {
+    fn get(&self, key: &Vec<u8>) -> Result<Vec<u8>, KvError> {
+        let spaces = self.spaces.read().unwrap();
+        match spaces.get(&self.space).and_then(|map| map.get(key)) {
+            Some(value) => Ok(value.clone()),
+            None => Err(KvError::NotFound("key not found".into())),
+        }
+    }
+
+    fn set(&self, key: &Vec<u8>, value: Vec<u8>) -> Result<(), KvError> {
+        let mut spaces = self.spaces.write().unwrap();
+        let map = spaces.entry(self.space.clone()).or_default();
+        map.insert(key.clone(), value);
+        Ok(())
+    }
+
+    fn write(&self, batch: WriteBatch) -> Result<(), KvError> {
+        let mut spaces = self.spaces.write().unwrap();
+        for (space, op) in batch.ops {
+            let map = spaces.entry(space).or_default();
+            match op {
+                BatchOp::Put(key, value) => map.insert(key, value),
+                BatchOp::Delete(key) => map.remove(&key),
+            };
+        }
+        Ok(())
+    }
+
+    // paged, the map is only locked while a page is copied
+    fn iter(&self, range: KeyRange) -> Result<KvIter<'_>, KvError> {
+        if range.is_empty() {
+            return Ok(Box::new(std::iter::empty()));
+        }
+        Ok(Box::new(MemoryIter {
+            kv: self,
+            range,
+            page: VecDeque::new(),
+        }))
+    }
+
+    fn remove(&self, key: &Vec<u8>) -> Result<(), KvError> {
+        let mut spaces = self.spaces.write().unwrap();
+        if let Some(map) = spaces.get_mut(&self.space) {
+            map.remove(key);
+        }
+        Ok(())
+    }
+
+    fn max_key(&self) -> Result<Vec<u8>, KvError> {
+        let spaces = self.spaces.read().unwrap();
+        match spaces
+            .get(&self.space)
+            .and_then(|map| map.keys().next_back())
+        {
+            Some(key) => Ok(key.clone()),
+            None => Err(KvError::NotFound("not found max key".into())),
+        }
+    }
+
+    fn space(&self) -> &str {
+        &self.space
+    }
+
+    fn open_space(&self, name: &str) -> Result<Box<dyn KvStore>, KvError> {
+        Ok(Box::new(MemoryKv {
+            spaces: self.spaces.clone(),
+            space: space_name(&self.space, name),
+        }))
+    }
+
+    fn checkpoint(&self, _dir: &str) -> Result<Option<DbKind>, KvError> {
+        Ok(None)
+    }
+}
+
+impl RocksDBKv {
+    // None for the default space
+    fn cf(&self) -> Result<Option<Arc<rocksdb::BoundColumnFamily<'_>>>, KvError> {
+        cf_of(&self.db, &self.space)
+    }
+}
+
+fn cf_of<'a>(
+    db: &'a RocksDB,
+    space: &str,
+) -> Result<Option<Arc<rocksdb::BoundColumnFamily<'a>>>, KvError> {
+    if space.is_empty() {
+        return Ok(None);
+    }
+    match db.cf_handle(space) {
+        Some(cf) => Ok(Some(cf)),
+        None => Err(KvError::NotFound(format!("space {} not found", space))),
+    }
 }
 
 impl KvStore for RocksDBKv {
     fn get(&self, key: &Vec<u8>) -> Result<Vec<u8>, KvError> {
-        match self.db.get(key.as_slice()) {
+        let r = match self.cf()? {
+            Some(cf) => self.db.get_cf(&cf, key.as_slice()),
+            None => self.db.get(key.as_slice()),
+        };
+        match r {
             Ok(value) => match value {
                 Some(value) => Ok(value.to_vec()),
                 None => Err(KvError::NotFound("key not found".into())),
@@ -142,44 +674,65 @@ impl KvStore for RocksDBKv {
         }
     }
     fn set(&self, key: &Vec<u8>, value: Vec<u8>) -> Result<(), KvError> {
-        let r = self.db.put(key, value);
+        let r = match self.cf()? {
+            Some(cf) => self.db.put_cf(&cf, key, value),
+            None => self.db.put(key, value),
+        };
         match r {
             Ok(_) => Ok(()),
             Err(err) => Err(KvError::IoError(err.to_string())),
         }
     }
 
-    fn scan(
-        &self,
-        start: &Vec<u8>,
-        end: &Vec<u8>,
-        limit: u32,
-        items: &mut Vec<(Vec<u8>, Vec<u8>)>,
-    ) -> Result<(), KvError> {
-        let start = start.as_slice();
-        let end = end.as_slice();
-        let mut ct = 0 as u32;
-        let it = self.db.iterator(rocksdb::IteratorMode::From(
-            start,
-            rocksdb::Direction::Forward,
-        ));
-        for (k, v) in it {
-            let k = k.as_ref();
-            let v = v.as_ref();
-            if k.cmp(end) != Ordering::Less {
-                break;
-            }
-            ct += 1;
-            if ct > limit {
-                break;
+    fn write(&self, batch: WriteBatch) -> Result<(), KvError> {
+        let mut write_opts = rocksdb::WriteOptions::default();
+        write_opts.set_sync(batch.sync);
+        let mut wb = rocksdb::WriteBatch::default();
+        for (space, ops) in batch.by_space() {
+            let cf = cf_of(&self.db, &space)?;
+            for op in ops {
+                match (&cf, op) {
+                    (Some(cf), BatchOp::Put(key, value)) => wb.put_cf(cf, key, value),
+                    (Some(cf), BatchOp::Delete(key)) => wb.delete_cf(cf, key),
+                    (None, BatchOp::Put(key, value)) => wb.put(key, value),
+                    (None, BatchOp::Delete(key)) => wb.delete(key),
+                }
             }
-            items.push((k.to_vec(), v.to_vec()));
         }
-        Ok(())
+        let r = self.db.write_opt(wb, &write_opts);
+        match r {
+            Ok(_) => Ok(()),
+            Err(err) => Err(KvError::IoError(err.to_string())),
+        }
+    }
+
+    fn iter(&self, range: KeyRange) -> Result<KvIter<'_>, KvError> {
+        if range.is_empty() {
+            return Ok(Box::new(std::iter::empty()));
+        }
+        let mode = match (range.reverse, &range.start, &range.end) {
+            (false, Bound::Unbounded, _) => rocksdb::IteratorMode::Start,
+            (false, Bound::Included(key), _) | (false, Bound::Excluded(key), _) => {
+                rocksdb::IteratorMode::From(key, rocksdb::Direction::Forward)
+            }
+            (true, _, Bound::Unbounded) => rocksdb::IteratorMode::End,
+            (true, _, Bound::Included(key)) | (true, _, Bound::Excluded(key)) => {
+                rocksdb::IteratorMode::From(key, rocksdb::Direction::Reverse)
+            }
+        };
+        let it = match self.cf()? {
+            Some(cf) => self.db.iterator_cf(&cf, mode),
+            None => self.db.iterator(mode),
+        };
+        let it = it.map(|(key, value)| (key.to_vec(), value.to_vec()));
+        Ok(bounded(it, range))
     }
 
     fn remove(&self, key: &Vec<u8>) -> Result<(), KvError> {
-        let r = self.db.delete(key.as_slice());
+        let r = match self.cf()? {
+            Some(cf) => self.db.delete_cf(&cf, key.as_slice()),
+            None => self.db.delete(key.as_slice()),
+        };
         match r {
             Ok(_) => Ok(()),
             Err(err) => Err(KvError::IoError(err.to_string())),
@@ -187,12 +740,45 @@ impl KvStore for RocksDBKv {
     }
 
     fn max_key(&self) -> Result<Vec<u8>, KvError> {
-        let it = self.db.iterator(rocksdb::IteratorMode::End);
+        let it = match self.cf()? {
+            Some(cf) => self.db.iterator_cf(&cf, rocksdb::IteratorMode::End),
+            None => self.db.iterator(rocksdb::IteratorMode::End),
+        };
         for (k, _v) in it {
             return Ok(k.as_ref().to_vec());
         }
         Err(KvError::NotFound("not found max key".into()))
     }
+
+    fn space(&self) -> &str {
+        &self.space
+    }
+
+    fn open_space(&self, name: &str) -> Result<Box<dyn KvStore>, KvError> {
+        let space = space_name(&self.space, name);
+        if self.db.cf_handle(&space).is_none() {
+            if let Err(err) = self.db.create_cf(&space, &rocksdb::Options::default()) {
+                return Err(KvError::IoError(err.to_string()));
+            }
+        }
+        Ok(Box::new(RocksDBKv {
+            db: self.db.clone(),
+            space,
+        }))
+    }
+
+    // hard links the sst files, a column family of a shared database is not alone in them
+    fn checkpoint(&self, dir: &str) -> Result<Option<DbKind>, KvError> {
+        if !self.space.is_empty() {
+            return Ok(None);
+        }
+        let r = rocksdb::checkpoint::Checkpoint::new(&self.db)
+            .and_then(|checkpoint| checkpoint.create_checkpoint(dir));
+        match r {
+            Ok(_) => Ok(Some(DbKind::ROCKSDB)),
+            Err(err) => Err(KvError::IoError(err.to_string())),
+        }
+    }
 }
 
 impl KvStore for Box<dyn KvStore> {
@@ -202,14 +788,11 @@ impl KvStore for Box<dyn KvStore> {
     fn set(&self, key: &Vec<u8>, value: Vec<u8>) -> Result<(), KvError> {
         self.as_ref().set(key, value)
     }
-    fn scan(
-        &self,
-        start: &Vec<u8>,
-        end: &Vec<u8>,
-        limit: u32,
-        items: &mut Vec<(Vec<u8>, Vec<u8>)>,
-    ) -> Result<(), KvError> {
-        self.as_ref().scan(start, end, limit, items)
+    fn write(&self, batch: WriteBatch) -> Result<(), KvError> {
+        self.as_ref().write(batch)
+    }
+    fn iter(&self, range: KeyRange) -> Result<KvIter<'_>, KvError> {
+        self.as_ref().iter(range)
     }
     fn remove(&self, key: &Vec<u8>) -> Result<(), KvError> {
         self.as_ref().remove(key)
@@ -217,4 +800,119 @@ impl KvStore for Box<dyn KvStore> {
     fn max_key(&self) -> Result<Vec<u8>, KvError> {
         self.as_ref().max_key()
     }
+    fn space(&self) -> &str {
+        self.as_ref().space()
+    }
+    fn open_space(&self, name: &str) -> Result<Box<dyn KvStore>, KvError> {
+        self.as_ref().open_space(name)
+    }
+    fn checkpoint(&self, dir: &str) -> Result<Option<DbKind>, KvError> {
+        self.as_ref().checkpoint(dir)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use temp_dir::TempDir;
+
+    fn collect_range(store: &dyn KvStore, range: KeyRange) -> Vec<(Vec<u8>, Vec<u8>)> {
+        store
+            .iter(range)
+            .unwrap()
+            .map(|item| item.unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn iter_ranges_on_every_engine() {
+        let tmp_dir = TempDir::new().unwrap();
+        let db_dir: String = tmp_dir.path().to_str().unwrap().into();
+        for db_kind in [DbKind::SLED, DbKind::ROCKSDB, DbKind::MEMORY] {
+            let dir = format!("{}/{}", db_dir, db_kind);
+            let store = new_kvstore(db_kind, dir).unwrap();
+            for key in [vec![1], vec![2, 0], vec![2, 1], vec![2, 255], vec![3]] {
+                store.set(&key, key.clone()).unwrap();
+            }
+            let keys = |range: KeyRange| -> Vec<Vec<u8>> {
+                collect_range(&store, range)
+                    .into_iter()
+                    .map(|(key, _)| key)
+                    .collect()
+            };
+            assert_eq!(keys(KeyRange::all()).len(), 5);
+            assert_eq!(
+                keys(KeyRange::prefix(&[2])),
+                vec![vec![2, 0], vec![2, 1], vec![2, 255]]
+            );
+            assert_eq!(
+                keys(KeyRange::prefix(&[2]).rev()),
+                vec![vec![2, 255], vec![2, 1], vec![2, 0]]
+            );
+            assert_eq!(
+                keys(KeyRange::between(vec![2, 1], vec![3]).rev()),
+                vec![vec![2, 255], vec![2, 1]]
+            );
+            assert_eq!(keys(KeyRange::after(vec![2, 255])), vec![vec![3]]);
+            assert_eq!(
+                keys(KeyRange::all().rev()).first().unwrap(),
+                &store.max_key().unwrap()
+            );
+            assert!(keys(KeyRange::between(vec![3], vec![1])).is_empty());
+        }
+    }
+
+    #[test]
+    fn memory_iter_and_max_key() {
+        let store = new_kvstore(DbKind::MEMORY, "root".into()).unwrap();
+        let index_store = store.open_space("index").unwrap();
+        assert!(matches!(store.max_key(), Err(KvError::NotFound(_))));
+        for i in [3u8, 1, 4, 5, 9, 2] {
+            store.set(&vec![i], vec![i * 10]).unwrap();
+        }
+        index_store.set(&vec![99], vec![0]).unwrap();
+        assert_eq!(store.max_key().unwrap(), vec![9]);
+        assert_eq!(index_store.max_key().unwrap(), vec![99]);
+        let items = collect_range(&store, KeyRange::between(vec![2], vec![5]));
+        assert_eq!(
+            items,
+            vec![
+                (vec![2], vec![20]),
+                (vec![3], vec![30]),
+                (vec![4], vec![40])
+            ]
+        );
+        let items: Vec<_> = store
+            .iter(KeyRange::between(vec![0], vec![255]))
+            .unwrap()
+            .take(2)
+            .map(|item| item.unwrap())
+            .collect();
+        assert_eq!(items, vec![(vec![1], vec![10]), (vec![2], vec![20])]);
+        let items = collect_range(&store, KeyRange::between(vec![5], vec![5]));
+        assert!(items.is_empty());
+        let mut batch = WriteBatch::default();
+        batch.delete(&store, vec![9]);
+        batch.put(&index_store, vec![1], vec![1]);
+        store.write(batch).unwrap();
+        assert_eq!(store.max_key().unwrap(), vec![5]);
+        assert_eq!(index_store.get(&vec![1]).unwrap(), vec![1]);
+        assert!(store.get(&vec![9]).is_err());
+        // ranges longer than a page, with a key added halfway through
+        let paged = store.open_space("paged").unwrap();
+        for i in 0..600u32 {
+            paged.set(&(i * 2).to_be_bytes().to_vec(), vec![]).unwrap();
+        }
+        let mut iter = paged.iter(KeyRange::all()).unwrap();
+        let first = iter.next().unwrap().unwrap().0;
+        assert_eq!(first, 0u32.to_be_bytes().to_vec());
+        paged.set(&1001u32.to_be_bytes().to_vec(), vec![]).unwrap();
+        assert_eq!(iter.count(), 600);
+        let keys: Vec<_> = collect_range(&*paged, KeyRange::all().rev())
+            .into_iter()
+            .map(|(key, _)| key)
+            .collect();
+        assert_eq!(keys.len(), 601);
+        assert!(keys.windows(2).all(|pair| pair[0] > pair[1]));
+    }
 }
@@ -0,0 +1,116 @@
+use std::str::FromStr;
+use strum_macros::{Display, EnumString};
+
+const COMPRESS_MIN_BYTES: u32 = 1024;
+const ZSTD_LEVEL: i32 = 3;
+// a protobuf encoding never starts with field 0, so raw values never carry it
+const COMPRESSED_MARK: u8 = 0;
+
+#[derive(Clone, Copy, Debug, PartialEq, Display, EnumString)]
+#[strum(serialize_all = "lowercase", ascii_case_insensitive)]
+pub enum Codec {
+    NONE,
+    ZSTD,
+    LZ4,
+}
+
+impl Codec {
+    fn tag(&self) -> u8 {
+        match self {
+            Codec::NONE => 0,
+            Codec::ZSTD => 1,
+            Codec::LZ4 => 2,
+        }
+    }
+}
+
+// None for an unknown codec, an empty compression means none
+pub fn parse_codec(compression: &str) -> Option<Codec> {
+    if compression.is_empty() {
+        return Some(Codec::NONE);
+    }
+    Codec::from_str(compression).ok()
+}
+
+pub struct Compression {
+    codec: Codec,
+    min_bytes: usize,
+}
+
+impl Compression {
+    pub fn new(compression: &str, min_bytes: u32) -> Self {
+        let min_bytes = match min_bytes {
+            0 => COMPRESS_MIN_BYTES,
+            min_bytes => min_bytes,
+        };
+        Compression {
+            codec: parse_codec(compression).unwrap_or(Codec::NONE),
+            min_bytes: min_bytes as usize,
+        }
+    }
+
+    // values below the threshold, or not getting smaller, are stored raw
+    pub fn compress(&self, value_buf: Vec<u8>) -> Vec<u8> {
+        let compressed = match self.encode(&value_buf) {
+            Some(compressed) => compressed,
+            None => return value_buf,
+        };
+        let mut marked = Vec::<u8>::with_capacity(compressed.len() + 2);
+        marked.push(COMPRESSED_MARK);
+        marked.push(self.codec.tag());
+        marked.extend_from_slice(&compressed);
+        marked
+    }
+
+    // a chunk can start with any byte, so a raw one is tagged as well
+    pub fn compress_chunk(&self, chunk: &[u8]) -> Vec<u8> {
+        let (tag, data) = match self.encode(chunk) {
+            Some(compressed) => (self.codec.tag(), compressed),
+            None => (Codec::NONE.tag(), chunk.to_vec()),
+        };
+        let mut tagged = Vec::<u8>::with_capacity(data.len() + 1);
+        tagged.push(tag);
+        tagged.extend_from_slice(&data);
+        tagged
+    }
+
+    // None when the value stays raw
+    fn encode(&self, value_buf: &[u8]) -> Option<Vec<u8>> {
+        if self.codec == Codec::NONE || value_buf.len() < self.min_bytes {
+            return None;
+        }
+        let compressed = match self.codec {
+            Codec::ZSTD => zstd::block::compress(value_buf, ZSTD_LEVEL).ok()?,
+            Codec::LZ4 => lz4_flex::compress_prepend_size(value_buf),
+            Codec::NONE => return None,
+        };
+        if compressed.len() + 2 >= value_buf.len() {
+            return None;
+        }
+        Some(compressed)
+    }
+}
+
+// the codec is read from each value, so a topic can switch codecs at any time
+pub fn decompress(value_buf: Vec<u8>) -> Result<Vec<u8>, String> {
+    if value_buf.len() < 2 || value_buf[0] != COMPRESSED_MARK {
+        return Ok(value_buf);
+    }
+    decode(value_buf[1], &value_buf[2..])
+}
+
+pub fn decompress_chunk(chunk: Vec<u8>) -> Result<Vec<u8>, String> {
+    match chunk.first() {
+        Some(0) => Ok(chunk[1..].to_vec()),
+        Some(tag) => decode(*tag, &chunk[1..]),
+        None => Err("empty chunk".into()),
+    }
+}
+
+fn decode(tag: u8, compressed: &[u8]) -> Result<Vec<u8>, String> {
+    match tag {
+        1 => zstd::decode_all(compressed).map_err(|err| err.to_string()),
+        2 => lz4_flex::decompress_size_prepended(compressed).map_err(|err| err.to_string()),
+        tag => Err(format!("unknown codec {}", tag)),
+    }
+}
@@ -0,0 +1,220 @@
+use crate::storage::kv::{KvError, KvStore, WriteBatch};
+use std::mem;
+use std::str::FromStr;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+use strum_macros::{Display, EnumString};
+use tokio::runtime::{Handle, RuntimeFlavor};
+use tokio::task;
+
+const GROUP_COMMIT_WINDOW: u32 = 2; //ms
+
+#[derive(Clone, Copy, Debug, PartialEq, Display, EnumString)]
+#[strum(serialize_all = "lowercase", ascii_case_insensitive)]
+pub enum Durability {
+    // acked once the write reaches the os, lost on power failure
+    NONE,
+    // every write is synced on its own
+    SYNC,
+    // writes arriving within the window share one synced write
+    GROUP,
+}
+
+// None for an unknown durability, an empty durability means none
+pub fn parse_durability(durability: &str) -> Option<Durability> {
+    if durability.is_empty() {
+        return Some(Durability::NONE);
+    }
+    Durability::from_str(durability).ok()
+}
+
+#[derive(Default)]
+struct Group {
+    batch: WriteBatch,
+    done: Option<Result<(), String>>,
+}
+
+type SharedGroup = Arc<(Mutex<Group>, Condvar)>;
+
+pub struct Committer {
+    durability: Durability,
+    window: Duration,
+    // the group still taking writes, its first writer commits it
+    open_group: Mutex<Option<SharedGroup>>,
+}
+
+impl Committer {
+    pub fn new(durability: &str, group_commit_ms: u32) -> Self {
+        let window = match group_commit_ms {
+            0 => GROUP_COMMIT_WINDOW,
+            window => window,
+        };
+        Committer {
+            durability: parse_durability(durability).unwrap_or(Durability::NONE),
+            window: Duration::from_millis(window as u64),
+            open_group: Mutex::new(None),
+        }
+    }
+
+    // returns once the batch is as durable as the topic asks for
+    pub fn write(&self, store: &dyn KvStore, mut batch: WriteBatch) -> Result<(), KvError> {
+        match self.durability {
+            Durability::NONE => store.write(batch),
+            Durability::SYNC => {
+                batch.set_sync(true);
+                blocking(|| store.write(batch))
+            }
+            Durability::GROUP => blocking(|| self.group_write(store, batch)),
+        }
+    }
+
+    fn group_write(&self, store: &dyn KvStore, batch: WriteBatch) -> Result<(), KvError> {
+        let (group, leader) = {
+            let mut open_group = self.open_group.lock().unwrap();
+            match open_group.as_ref() {
+                Some(group) => {
+                    group.0.lock().unwrap().batch.append(batch);
+                    (group.clone(), false)
+                }
+                None => {
+                    let group: SharedGroup = Arc::default();
+                    group.0.lock().unwrap().batch = batch;
+                    *open_group = Some(group.clone());
+                    (group, true)
+                }
+            }
+        };
+        let (lock, cond) = &*group;
+        if leader {
+            thread::sleep(self.window);
+            // closed before taking the batch, later writers start the next group
+            self.open_group.lock().unwrap().take();
+            let mut batch = mem::take(&mut lock.lock().unwrap().batch);
+            batch.set_sync(true);
+            let result = store.write(batch).map_err(|err| err.to_string());
+            lock.lock().unwrap().done = Some(result);
+            cond.notify_all();
+        }
+        let mut state = lock.lock().unwrap();
+        while state.done.is_none() {
+            state = cond.wait(state).unwrap();
+        }
+        match state.done.as_ref().unwrap() {
+            Ok(_) => Ok(()),
+            Err(err) => Err(KvError::IoError(err.clone())),
+        }
+    }
+}
+
+// the window sleep and the sync block their thread, a runtime worker hands its other tasks off first
+fn blocking<T>(f: impl FnOnce() -> T) -> T {
+    match Handle::try_current() {
+        Ok(handle) if handle.runtime_flavor() == RuntimeFlavor::MultiThread => {
+            task::block_in_place(f)
+        }
+        _ => f(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::kv::{self, DbKind, KeyRange, KvIter};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // counts the writes reaching the store and the synced ones among them
+    struct CountingKv {
+        inner: Box<dyn KvStore>,
+        writes: Arc<AtomicUsize>,
+        synced: Arc<AtomicUsize>,
+    }
+
+    impl KvStore for CountingKv {
+        fn get(&self, key: &Vec<u8>) -> Result<Vec<u8>, KvError> {
+            self.inner.get(key)
+        }
+        fn set(&self, key: &Vec<u8>, value: Vec<u8>) -> Result<(), KvError> {
+            self.inner.set(key, value)
+        }
+        fn write(&self, batch: WriteBatch) -> Result<(), KvError> {
+            self.writes.fetch_add(1, Ordering::SeqCst);
+            if batch.is_sync() {
+                self.synced.fetch_add(1, Ordering::SeqCst);
+            }
+            self.inner.write(batch)
+        }
+        fn iter(&self, range: KeyRange) -> Result<KvIter<'_>, KvError> {
+            self.inner.iter(range)
+        }
+        fn remove(&self, key: &Vec<u8>) -> Result<(), KvError> {
+            self.inner.remove(key)
+        }
+        fn max_key(&self) -> Result<Vec<u8>, KvError> {
+            self.inner.max_key()
+        }
+        fn space(&self) -> &str {
+            self.inner.space()
+        }
+        fn open_space(&self, name: &str) -> Result<Box<dyn KvStore>, KvError> {
+            self.inner.open_space(name)
+        }
+        fn checkpoint(&self, dir: &str) -> Result<Option<DbKind>, KvError> {
+            self.inner.checkpoint(dir)
+        }
+    }
+
+    fn counting_store() -> Arc<CountingKv> {
+        Arc::new(CountingKv {
+            inner: kv::new_kvstore(DbKind::MEMORY, "root".into()).unwrap(),
+            writes: Arc::default(),
+            synced: Arc::default(),
+        })
+    }
+
+    fn one_put(store: &dyn KvStore, key: u8) -> WriteBatch {
+        let mut batch = WriteBatch::default();
+        batch.put(store, vec![key], vec![key]);
+        batch
+    }
+
+    #[test]
+    fn sync_and_none_writes() {
+        let store = counting_store();
+        let committer = Committer::new("sync", 0);
+        for key in 0..3 {
+            committer.write(&*store, one_put(&*store, key)).unwrap();
+        }
+        assert_eq!(store.writes.load(Ordering::SeqCst), 3);
+        assert_eq!(store.synced.load(Ordering::SeqCst), 3);
+        let committer = Committer::new("", 0);
+        committer.write(&*store, one_put(&*store, 3)).unwrap();
+        assert_eq!(store.writes.load(Ordering::SeqCst), 4);
+        assert_eq!(store.synced.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn group_writes_share_a_sync() {
+        let store = counting_store();
+        let committer = Arc::new(Committer::new("group", 100));
+        // more writers than runtime workers, a blocked worker would split the group
+        let writers: Vec<_> = (0..8u8)
+            .map(|key| {
+                let store = store.clone();
+                let committer = committer.clone();
+                tokio::spawn(async move {
+                    committer.write(&*store, one_put(&*store, key)).unwrap();
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.await.unwrap();
+        }
+        let writes = store.writes.load(Ordering::SeqCst);
+        assert_eq!(store.synced.load(Ordering::SeqCst), writes);
+        assert!(writes <= 2, "{} synced writes for 8 batches", writes);
+        for key in 0..8u8 {
+            assert_eq!(store.get(&vec![key]).unwrap(), vec![key]);
+        }
+    }
+}
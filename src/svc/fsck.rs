@@ -0,0 +1,120 @@
+use crate::storage::kv::{KeyRange, KvError, KvStore};
+use crate::svc::codec;
+use crate::svc::priority_queue;
+use crate::svc::priority_queue::bettermq::{EnqueueRequest, InnerIndex};
+use prost::Message;
+use std::collections::HashSet;
+use std::str::FromStr;
+use strum_macros::{Display, EnumString};
+
+#[derive(Clone, Copy, Debug, PartialEq, Display, EnumString)]
+#[strum(serialize_all = "lowercase", ascii_case_insensitive)]
+pub enum Repair {
+    // only report what is wrong
+    NONE,
+    // index orphan payloads again, unreadable payloads are unindexed and kept
+    REINDEX,
+    // delete orphan and unreadable payloads
+    DELETE,
+    // move orphan and unreadable payloads to another topic
+    QUARANTINE,
+}
+
+// None for an unknown repair, an empty repair means none
+pub fn parse_repair(repair: &str) -> Option<Repair> {
+    if repair.is_empty() {
+        return Some(Repair::NONE);
+    }
+    Repair::from_str(repair).ok()
+}
+
+#[derive(Default)]
+pub struct Findings {
+    pub messages: u64,
+    // readable payloads without a readable index entry
+    pub orphan_payloads: Vec<(Vec<u8>, EnqueueRequest)>,
+    // payloads that do not decode or miss chunks
+    pub broken_payloads: Vec<Vec<u8>>,
+    // index entries without a payload
+    pub orphan_indexes: Vec<Vec<u8>>,
+    // index entries that do not decode, their payload is counted as orphan or broken
+    pub broken_indexes: Vec<Vec<u8>>,
+    // chunk keys of messages without a payload
+    pub orphan_chunks: Vec<Vec<u8>>,
+}
+
+// a kept key holds no message and is skipped, an index entry on it is an orphan
+pub fn scan(
+    msg_store: &dyn KvStore,
+    index_store: &dyn KvStore,
+    chunk_store: &dyn KvStore,
+) -> Result<Findings, KvError> {
+    let mut findings = Findings::default();
+    let mut indexed = HashSet::<Vec<u8>>::new();
+    let mut unreadable = HashSet::<Vec<u8>>::new();
+    for item in index_store.iter(KeyRange::all())? {
+        let (key, value_buf) = item?;
+        // dedup and schedule keys are longer than a message id
+        if key.len() != 8 {
+            continue;
+        }
+        match InnerIndex::decode(value_buf.as_slice()) {
+            Ok(_) => indexed.insert(key),
+            Err(_) => unreadable.insert(key),
+        };
+    }
+    let mut stored = HashSet::<Vec<u8>>::new();
+    for item in msg_store.iter(KeyRange::all())? {
+        let (message_id, value_buf) = item?;
+        if value_buf == priority_queue::KEPT_VALUE {
+            continue;
+        }
+        findings.messages += 1;
+        let has_index = indexed.remove(&message_id);
+        if unreadable.remove(&message_id) {
+            findings.broken_indexes.push(message_id.clone());
+        }
+        match decode_payload(chunk_store, &message_id, value_buf) {
+            Some(_) if has_index => {}
+            Some(request) => findings.orphan_payloads.push((message_id.clone(), request)),
+            None => findings.broken_payloads.push(message_id.clone()),
+        }
+        stored.insert(message_id);
+    }
+    findings.orphan_indexes.extend(indexed);
+    findings.orphan_indexes.extend(unreadable);
+    findings.orphan_indexes.sort();
+    for item in chunk_store.iter(KeyRange::all())? {
+        let (key, _) = item?;
+        if key.len() < 8 || !stored.contains(&key[0..8]) {
+            findings.orphan_chunks.push(key);
+        }
+    }
+    Ok(findings)
+}
+
+// None when the value or one of its chunks can not be read
+fn decode_payload(
+    chunk_store: &dyn KvStore,
+    message_id: &Vec<u8>,
+    value_buf: Vec<u8>,
+) -> Option<EnqueueRequest> {
+    let value_buf = codec::decompress(value_buf).ok()?;
+    let request = EnqueueRequest::decode(value_buf.as_slice()).ok()?;
+    if !request.payload.is_empty() {
+        return Some(request);
+    }
+    let (total, tagged) = match chunk_store.get(message_id) {
+        Ok(total_buf) => priority_queue::decode_chunk_meta(&total_buf)?,
+        Err(_) => (0, false),
+    };
+    for chunk_no in 0..total {
+        let mut key = message_id.clone();
+        key.extend_from_slice(&chunk_no.to_be_bytes());
+        let chunk = chunk_store.get(&key).ok()?;
+        if tagged {
+            codec::decompress_chunk(chunk).ok()?;
+        }
+    }
+    Some(request)
+}
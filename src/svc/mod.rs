@@ -1,4 +1,7 @@
+mod codec;
+mod commit;
+mod fsck;
 pub mod multi_queue;
 mod priority_queue;
 mod utils;
-mod worker;
+pub mod worker;
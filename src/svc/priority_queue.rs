@@ -1,25 +1,194 @@
-use crate::storage::kv::KvStore;
+use crate::storage::kv;
+use crate::storage::kv::{DbKind, KeyRange, KvError, KvStore, WriteBatch};
+use crate::svc::codec::{self, Compression};
+use crate::svc::commit::Committer;
+use crate::svc::fsck::{self, Findings, Repair};
 use crate::svc::utils;
-use crate::svc::worker::{TaskItem, Worker};
+use crate::svc::worker::{ExpireHandler, ExtendError, TaskItem, WaitingTasks, Worker};
+use bettermq::DedupEntry;
+use bettermq::PayloadChunk;
+use bettermq::SubscribeRequest;
+use bettermq::TopicOptions;
 use bettermq::TopicStats;
+use bettermq::{AckBatchReply, AckBatchRequest, MessageResult};
 use bettermq::{AckReply, AckRequest};
+use bettermq::{CheckTopicReply, CheckTopicRequest};
 use bettermq::{DataItem, DequeueReply, DequeueRequest};
+use bettermq::{DeleteScheduleReply, DeleteScheduleRequest};
+use bettermq::{EnqueueBatchReply, EnqueueBatchRequest};
 use bettermq::{EnqueueReply, EnqueueRequest, InnerIndex};
+use bettermq::{ExtendLeaseReply, ExtendLeaseRequest};
+use bettermq::{ListSchedulesReply, ListSchedulesRequest};
+use bettermq::{NackBatchReply, NackBatchRequest};
 use bettermq::{NackReply, NackRequest};
+use bettermq::{Schedule, ScheduleRecurringReply, ScheduleRecurringRequest};
+use chrono::{DateTime, Utc};
+use cron::Schedule as CronSchedule;
 use prost::Message;
 use rayon::prelude::*;
-use std::sync::{Arc, RwLock};
+use std::collections::{HashMap, HashSet};
+use std::mem;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, UNIX_EPOCH};
+use tokio::sync::{mpsc, Notify};
+use tokio::time;
+use tokio_stream::{Stream, StreamExt};
 use tonic::{Request, Response, Status};
-use tracing::{info, trace};
+use tracing::{info, trace, warn};
 
 pub mod bettermq {
     tonic::include_proto!("bettermq");
 }
 
+const SUBSCRIBE_POLL_INTERVAL: u64 = 1000; //ms
+const DEAD_LETTER_RETRY: i32 = 5000; //ms
+const DEAD_LETTER_RETRIES: u32 = 3;
+// sorts after every 8 bytes message id, so rebuild_index never sees it
+const DEDUP_PREFIX: &[u8] = b"\xff\xff\xff\xff\xff\xff\xff\xffdedup/";
+const DEDUP_WINDOW: u32 = 300000; //ms
+const DEDUP_PRUNE_INTERVAL: u64 = 60000; //ms
+const SCHEDULE_PREFIX: &[u8] = b"\xff\xff\xff\xff\xff\xff\xff\xffschedule/";
+// under SCHEDULE_PREFIX, a deleted schedule id is never handed out again
+const SCHEDULE_LAST_ID: &[u8] = b"last_id";
+const SCHEDULE_IDLE: u64 = 1000; //ms
+const CHUNK_BYTES: u32 = 1048576;
+const RELEASE_POLL: u64 = 50; //ms
+pub const INDEX_SPACE: &str = "index";
+// <message_id> holds the chunk count, <message_id><chunk no> a chunk of the payload
+pub const CHUNK_SPACE: &str = "chunk";
+// follows the chunk count when every chunk leads with its codec tag
+const CHUNKS_TAGGED: u8 = 1;
+// stands in for the deleted payload of the max seq_no, a protobuf encoding never starts
+// with field 0 and a compressed value is longer, so no message is ever stored as it
+pub const KEPT_VALUE: &[u8] = &[0];
+
+// copies the messages of a topic with their index and payload chunks; the index goes
+// first, so a copy of a live topic only misses index entries of messages enqueued meanwhile
+pub fn copy_topic(src: &dyn KvStore, dst: &dyn KvStore) -> Result<u64, KvError> {
+    let src_index = src.open_space(INDEX_SPACE)?;
+    let dst_index = dst.open_space(INDEX_SPACE)?;
+    let mut total = kv::copy_all(&src_index, &dst_index)?;
+    total += kv::copy_all(src, dst)?;
+    let src_chunks = src.open_space(CHUNK_SPACE)?;
+    let dst_chunks = dst.open_space(CHUNK_SPACE)?;
+    total += kv::copy_all(&src_chunks, &dst_chunks)?;
+    Ok(total)
+}
+
+pub type DeadLetterSink = Arc<dyn Fn(EnqueueRequest) -> Result<EnqueueReply, Status> + Send + Sync>;
+
 struct SharedState {
     msg_store: Box<dyn KvStore>,
     index_store: Box<dyn KvStore>,
-    seq_no: u64,
+    chunk_store: Box<dyn KvStore>,
+    // bumped under the read lock, so enqueues never wait on each other's writes
+    seq_no: AtomicU64,
+}
+
+impl SharedState {
+    fn put_message(
+        &self,
+        batch: &mut WriteBatch,
+        message_id: &[u8],
+        value_buf: Vec<u8>,
+        index_buf: Vec<u8>,
+        chunks: Vec<Vec<u8>>,
+    ) {
+        batch.put(&self.msg_store, message_id.to_vec(), value_buf);
+        batch.put(&self.index_store, message_id.to_vec(), index_buf);
+        if chunks.is_empty() {
+            return;
+        }
+        let total = chunks.len() as u32;
+        let mut total_buf = total.to_be_bytes().to_vec();
+        total_buf.push(CHUNKS_TAGGED);
+        batch.put(&self.chunk_store, message_id.to_vec(), total_buf);
+        for (chunk_no, chunk) in (0..total).zip(chunks) {
+            batch.put(&self.chunk_store, chunk_key(message_id, chunk_no), chunk);
+        }
+    }
+
+    // the key of the max seq_no is kept as KEPT_VALUE, it recovers seq_no on restart
+    fn delete_message(&self, batch: &mut WriteBatch, message_id: Vec<u8>) {
+        let total = self.chunk_count(&message_id);
+        for chunk_no in 0..total {
+            batch.delete(&self.chunk_store, chunk_key(&message_id, chunk_no));
+        }
+        if total > 0 {
+            batch.delete(&self.chunk_store, message_id.clone());
+        }
+        match utils::msgid_to_u64(&message_id) == self.seq_no.load(Ordering::SeqCst) {
+            true => batch.put(&self.msg_store, message_id.clone(), KEPT_VALUE.to_vec()),
+            false => batch.delete(&self.msg_store, message_id.clone()),
+        }
+        batch.delete(&self.index_store, message_id);
+    }
+
+    // a kept key is not found, its message is gone
+    fn get_value(&self, message_id: &Vec<u8>) -> Result<Vec<u8>, KvError> {
+        let value_buf = self.msg_store.get(message_id)?;
+        if value_buf == KEPT_VALUE {
+            return Err(KvError::NotFound("key not found".into()));
+        }
+        Ok(value_buf)
+    }
+
+    // 0 when the payload is stored inline
+    fn chunk_count(&self, message_id: &Vec<u8>) -> u32 {
+        self.chunk_meta(message_id).0
+    }
+
+    fn chunk_meta(&self, message_id: &Vec<u8>) -> (u32, bool) {
+        match self.chunk_store.get(message_id) {
+            Ok(total_buf) => decode_chunk_meta(&total_buf).unwrap_or((0, false)),
+            Err(_) => (0, false),
+        }
+    }
+
+    fn load_chunk(
+        &self,
+        message_id: &Vec<u8>,
+        chunk_no: u32,
+        tagged: bool,
+    ) -> Result<Vec<u8>, KvError> {
+        let chunk = self.chunk_store.get(&chunk_key(message_id, chunk_no))?;
+        match tagged {
+            true => codec::decompress_chunk(chunk).map_err(KvError::IoError),
+            false => Ok(chunk),
+        }
+    }
+
+    // decodes a stored message with its chunks joined back into the payload
+    fn load_message(&self, message_id: &Vec<u8>) -> Result<EnqueueRequest, KvError> {
+        let value_buf = self.get_value(message_id)?;
+        let mut req = decode_request(value_buf);
+        let (total, tagged) = self.chunk_meta(message_id);
+        for chunk_no in 0..total {
+            req.payload
+                .extend_from_slice(&self.load_chunk(message_id, chunk_no, tagged)?);
+        }
+        Ok(req)
+    }
+}
+
+// the chunk count and whether the chunks are tagged, chunks written untagged are raw
+pub fn decode_chunk_meta(total_buf: &[u8]) -> Option<(u32, bool)> {
+    let tagged = match total_buf.len() {
+        4 => false,
+        5 if total_buf[4] == CHUNKS_TAGGED => true,
+        _ => return None,
+    };
+    let mut dst = [0u8; 4];
+    dst.clone_from_slice(&total_buf[..4]);
+    Some((u32::from_be_bytes(dst), tagged))
+}
+
+fn chunk_key(message_id: &[u8], chunk_no: u32) -> Vec<u8> {
+    let mut key = message_id.to_vec();
+    key.extend_from_slice(&chunk_no.to_be_bytes());
+    key
 }
 
 pub struct PriorityQueueSvc {
@@ -27,13 +196,44 @@ pub struct PriorityQueueSvc {
     node_id: String,
     topic: String,
     worker: Box<Worker>,
+    options: TopicOptions,
+    compression: Compression,
+    chunk_bytes: usize,
+    committer: Committer,
+    dead_letter: Option<DeadLetterSink>,
+    dedup_lock: Mutex<u64>, //last prune time
+    schedules: Mutex<HashMap<u64, Schedule>>,
+    last_schedule_id: AtomicU64, //changed under the schedules lock
+    schedule_notifier: Arc<Notify>,
+    // wakes subscribe and fetch_payload tasks waiting on their client once stopped
+    stop_notifier: Notify,
+}
+
+// the storage of a stopped topic, free once nothing holds the topic any more
+#[derive(Clone)]
+pub struct Releasing(std::sync::Weak<RwLock<SharedState>>);
+
+impl Releasing {
+    // false when the storage is still held after timeout
+    pub async fn wait(&self, timeout: Duration) -> bool {
+        let deadline = time::Instant::now() + timeout;
+        while self.0.strong_count() > 0 {
+            if time::Instant::now() >= deadline {
+                return false;
+            }
+            time::sleep(Duration::from_millis(RELEASE_POLL)).await;
+        }
+        true
+    }
 }
 
+// the index lives in a space of the message store, so both commit together
 pub fn make_one_queue(
     msg_store: Box<dyn KvStore>,
-    index_store: Box<dyn KvStore>,
     node_id: &String,
     topic: &String,
+    options: TopicOptions,
+    dead_letter: Option<DeadLetterSink>,
 ) -> PriorityQueueSvc {
     let mut seq_no: u64 = 0;
     match msg_store.max_key() {
@@ -44,52 +244,289 @@ pub fn make_one_queue(
         }
         Err(_) => {}
     }
-    let mut worker = Worker::default();
+    let index_store = msg_store.open_space(INDEX_SPACE).unwrap();
+    let chunk_store = msg_store.open_space(CHUNK_SPACE).unwrap();
+    let state = Arc::new(RwLock::new(SharedState {
+        msg_store: msg_store,
+        index_store: index_store,
+        chunk_store: chunk_store,
+        seq_no: AtomicU64::new(seq_no),
+    }));
+    let mut worker = Worker::new(options.worker_shards);
+    worker.set_aging(options.aging_ms);
+    worker.set_rate_limit(options.rate_limit, options.rate_burst);
+    worker.set_expire_handler(expire_handler(
+        state.clone(),
+        topic.clone(),
+        options.dead_letter_topic.clone(),
+        dead_letter.clone(),
+        worker.waiting_tasks(),
+    ));
     let _worker_r = worker.start();
-    rebuild_index(&index_store, &worker);
+    rebuild_index(&state.read().unwrap().index_store, &worker);
+    let (schedules, last_schedule_id) = load_schedules(&state.read().unwrap().index_store);
     let service = PriorityQueueSvc {
-        state: Arc::new(RwLock::new(SharedState {
-            msg_store: msg_store,
-            index_store: index_store,
-            seq_no: seq_no,
-        })),
+        state: state,
         node_id: node_id.clone(),
         topic: topic.clone(),
         worker: Box::new(worker),
+        compression: Compression::new(&options.compression, options.compress_min_bytes),
+        chunk_bytes: match options.chunk_bytes {
+            0 => CHUNK_BYTES as usize,
+            chunk_bytes => chunk_bytes as usize,
+        },
+        committer: Committer::new(&options.durability, options.group_commit_ms),
+        options,
+        dead_letter,
+        dedup_lock: Mutex::new(0),
+        schedules: Mutex::new(schedules),
+        last_schedule_id: AtomicU64::new(last_schedule_id),
+        schedule_notifier: Arc::new(Notify::new()),
+        stop_notifier: Notify::new(),
     };
     info!("seq_no: {:?}", seq_no);
     service
 }
 
 fn rebuild_index(index_store: &Box<dyn KvStore>, worker: &Worker) {
-    let mut start = vec![0 as u8; 1];
-    let end = vec![255 as u8; 8];
+    // message ids only, dedup and schedule keys sort after them
+    let range = KeyRange::between(vec![0 as u8; 1], vec![255 as u8; 8]);
     let mut total = 0 as u64;
-    loop {
-        let mut buffer = Vec::<(Vec<u8>, Vec<u8>)>::with_capacity(100);
-        let scan_result = index_store.scan(&start, &end, 100, &mut buffer);
-        if buffer.len() == 0 {
-            break;
-        } else {
-            total += buffer.len() as u64;
-        }
-        info!("rebuild {:} items", total);
-        if !scan_result.is_err() {
-            for (k, v) in buffer {
-                start = k.clone();
-                let inner_index = InnerIndex::decode(v.as_slice()).unwrap();
-                let task_item = TaskItem {
-                    priority: inner_index.priority,
-                    timestamp: inner_index.timestamp,
-                    message_id: inner_index.message_id,
-                };
-                worker.add_task(task_item);
+    for item in index_store.iter(range).expect("rebuild index failed") {
+        let (_, v) = item.expect("rebuild index failed");
+        let inner_index = InnerIndex::decode(v.as_slice()).unwrap();
+        let task_item = TaskItem {
+            priority: inner_index.priority,
+            timestamp: inner_index.timestamp,
+            message_id: inner_index.message_id,
+            deliveries: inner_index.deliveries,
+            expire_at: inner_index.expire_at,
+            group_key: inner_index.group_key,
+        };
+        worker.add_task(task_item);
+        total += 1;
+        if total % 100 == 0 {
+            info!("rebuild {:} items", total);
+        }
+    }
+    info!("rebuild {:} items", total);
+}
+
+fn parse_message_ids(s_message_ids: &Vec<String>) -> (Vec<Vec<u8>>, Vec<MessageResult>) {
+    let mut message_ids = Vec::<Vec<u8>>::with_capacity(s_message_ids.len());
+    let mut results = Vec::<MessageResult>::with_capacity(s_message_ids.len());
+    for s_message_id in s_message_ids {
+        let mut result = MessageResult {
+            message_id: s_message_id.clone(),
+            ok: false,
+            error: "".into(),
+        };
+        match s_message_id.parse::<u64>() {
+            Ok(n) => message_ids.push(n.to_be_bytes().to_vec()),
+            Err(_) => {
+                message_ids.push(vec![]);
+                result.error = "invalid message id".into();
             }
-            start.extend(vec![0 as u8; 1])
-        } else {
-            panic!("rebuild index failed");
         }
+        results.push(result);
+    }
+    (message_ids, results)
+}
+
+fn expire_handler(
+    state: Arc<RwLock<SharedState>>,
+    topic: String,
+    dead_letter_topic: String,
+    dead_letter: Option<DeadLetterSink>,
+    waiting: WaitingTasks,
+) -> ExpireHandler {
+    Arc::new(move |message_ids: Vec<Vec<u8>>| {
+        // a nack may have put some back since the worker expired them
+        let message_ids: Vec<Vec<u8>> = message_ids
+            .into_iter()
+            .filter(|message_id| !waiting.contains(message_id))
+            .collect();
+        let state_r = state.read().unwrap();
+        for message_id in &message_ids {
+            let s_message_id = utils::msgid_to_str(message_id);
+            match forward_dead_letter(
+                &state_r,
+                &topic,
+                &dead_letter_topic,
+                &dead_letter,
+                message_id,
+            ) {
+                Some(Ok(reply)) => info!(
+                    "move expired {:}:{:} to {:}:{:}",
+                    topic, s_message_id, dead_letter_topic, reply.message_id
+                ),
+                Some(Err(err)) => {
+                    warn!("dead letter {:}:{:} failed: {:}", topic, s_message_id, err)
+                }
+                None => trace!("drop expired {:}:{:}", topic, s_message_id),
+            }
+        }
+        drop(state_r);
+        // a nack adds its message back under the read lock, so it can not slip in here
+        let state = state.write().unwrap();
+        let message_ids = message_ids
+            .into_iter()
+            .filter(|message_id| !waiting.contains(message_id))
+            .collect();
+        if let Err(err) = remove_msgs(&state, message_ids) {
+            warn!("remove expired messages failed: {:}", err);
+        }
+    })
+}
+
+fn forward_dead_letter(
+    state: &SharedState,
+    topic: &str,
+    dead_letter_topic: &str,
+    dead_letter: &Option<DeadLetterSink>,
+    message_id: &Vec<u8>,
+) -> Option<Result<EnqueueReply, Status>> {
+    let dead_letter = dead_letter.as_ref()?;
+    if dead_letter_topic.is_empty() || dead_letter_topic == topic {
+        return None;
+    }
+    let mut req = state.load_message(message_id).ok()?;
+    req.topic = dead_letter_topic.into();
+    req.deliver_after = 0;
+    req.ttl_ms = 0;
+    req.dedup_key = "".into();
+    Some(dead_letter(req))
+}
+
+// values are stored encoded and maybe compressed, see encode_message
+fn decode_request(value_buf: Vec<u8>) -> EnqueueRequest {
+    let value_buf = codec::decompress(value_buf).unwrap();
+    EnqueueRequest::decode(value_buf.as_slice()).unwrap()
+}
+
+fn remove_msgs(state: &SharedState, message_ids: Vec<Vec<u8>>) -> Result<(), KvError> {
+    let mut batch = WriteBatch::default();
+    for message_id in message_ids {
+        state.delete_message(&mut batch, message_id);
+    }
+    state.msg_store.write(batch)
+}
+
+// also the last schedule id handed out
+fn load_schedules(index_store: &Box<dyn KvStore>) -> (HashMap<u64, Schedule>, u64) {
+    let mut schedules = HashMap::<u64, Schedule>::new();
+    let mut last_id = 0;
+    let range = KeyRange::prefix(SCHEDULE_PREFIX);
+    for item in index_store.iter(range).expect("load schedules failed") {
+        let (k, v) = item.expect("load schedules failed");
+        if k == schedule_last_id_key() {
+            last_id = last_id.max(utils::msgid_to_u64(&v));
+            continue;
+        }
+        let schedule = Schedule::decode(v.as_slice()).unwrap();
+        let schedule_id = schedule.schedule_id.parse::<u64>().unwrap();
+        last_id = last_id.max(schedule_id);
+        schedules.insert(schedule_id, schedule);
+    }
+    info!("load {:} schedules", schedules.len());
+    (schedules, last_id)
+}
+
+fn schedule_last_id_key() -> Vec<u8> {
+    let mut key = SCHEDULE_PREFIX.to_vec();
+    key.extend_from_slice(SCHEDULE_LAST_ID);
+    key
+}
+
+fn schedule_index_key(schedule_id: u64) -> Vec<u8> {
+    let mut key = SCHEDULE_PREFIX.to_vec();
+    key.extend_from_slice(&schedule_id.to_be_bytes());
+    key
+}
+
+fn next_fire_at(schedule: &Schedule, now: u64) -> Option<u64> {
+    if schedule.cron.is_empty() {
+        let interval = schedule.interval_ms as u64;
+        if interval == 0 {
+            return None;
+        }
+        if schedule.next_fire_at == 0 {
+            return Some(now + interval);
+        }
+        // keep the cadence, runs missed while down are skipped
+        let missed = now.saturating_sub(schedule.next_fire_at) / interval;
+        return Some(schedule.next_fire_at + (missed + 1) * interval);
+    }
+    let cron = CronSchedule::from_str(&schedule.cron).ok()?;
+    let now: DateTime<Utc> = (UNIX_EPOCH + Duration::from_millis(now)).into();
+    cron.after(&now)
+        .next()
+        .map(|fire_at| fire_at.timestamp_millis() as u64)
+}
+
+fn dedup_index_key(dedup_key: &str) -> Vec<u8> {
+    let mut key = DEDUP_PREFIX.to_vec();
+    key.extend_from_slice(dedup_key.as_bytes());
+    key
+}
+
+fn encode_index(task: &TaskItem) -> Vec<u8> {
+    let mut index_buf = Vec::<u8>::with_capacity(100);
+    let inner_index = InnerIndex {
+        priority: task.priority,
+        timestamp: task.timestamp,
+        message_id: task.message_id.clone(),
+        deliveries: task.deliveries,
+        expire_at: task.expire_at,
+        group_key: task.group_key.clone(),
+    };
+    let _r = inner_index.encode(&mut index_buf);
+    index_buf
+}
+
+fn encode_message(
+    cur_seq: u64,
+    request: &EnqueueRequest,
+    deliveries: u32,
+    expire_at: u64,
+    compression: &Compression,
+    chunk_bytes: usize,
+) -> (TaskItem, Vec<u8>, Vec<u8>, Vec<Vec<u8>>) {
+    let now = utils::timestamp();
+    let task_item = TaskItem {
+        priority: request.priority,
+        timestamp: now + request.deliver_after as u64,
+        message_id: cur_seq.to_be_bytes().to_vec(),
+        deliveries,
+        expire_at,
+        group_key: request.group_key.clone(),
+    };
+    let mut value_buf = Vec::<u8>::with_capacity(200);
+    let mut chunks = Vec::<Vec<u8>>::new();
+    if request.payload.len() > chunk_bytes {
+        // the stored message keeps everything but the payload
+        let stored = EnqueueRequest {
+            topic: request.topic.clone(),
+            payload: Vec::new(),
+            priority: request.priority,
+            deliver_after: request.deliver_after,
+            meta: request.meta.clone(),
+            ttl_ms: request.ttl_ms,
+            dedup_key: request.dedup_key.clone(),
+            group_key: request.group_key.clone(),
+        };
+        let _r = stored.encode(&mut value_buf);
+        chunks = request
+            .payload
+            .chunks(chunk_bytes)
+            .map(|chunk| compression.compress_chunk(chunk))
+            .collect();
+    } else {
+        let _r = request.encode(&mut value_buf);
     }
+    let value_buf = compression.compress(value_buf);
+    let index_buf = encode_index(&task_item);
+    (task_item, value_buf, index_buf, chunks)
 }
 
 impl PriorityQueueSvc {
@@ -98,56 +535,144 @@ impl PriorityQueueSvc {
         request: Request<EnqueueRequest>,
     ) -> Result<Response<EnqueueReply>, Status> {
         trace!("{:?}", request);
-        let cur_seq: u64;
-        {
-            let mut state = self.state.write().unwrap();
-            state.seq_no += 1;
-            cur_seq = state.seq_no;
+        let dedup_key = request.get_ref().dedup_key.clone();
+        let _dedup_guard = self.lock_dedup(dedup_key.is_empty());
+        if let Some(message_id) = self.find_dedup(&dedup_key)? {
+            let reply = EnqueueReply {
+                message_id,
+                node_id: self.node_id.clone(),
+            };
+            return Ok(Response::new(reply));
         }
-        let result = self.enqueue_with_id(cur_seq, request);
+        let cur_seq = self.next_seq(1);
+        let expire_at = self.expire_at(request.get_ref());
+        let mut batch = WriteBatch::default();
+        if !dedup_key.is_empty() {
+            self.save_dedup(&mut batch, vec![(dedup_key, cur_seq)]);
+        }
+        let result = self.enqueue_with_id(cur_seq, request, 0, expire_at, batch);
         match result {
             Ok(value) => Ok(Response::new(value)),
             Err(err) => Err(err),
         }
     }
 
+    // payloads, indexes and dedup keys of every item go in one write batch
+    pub fn enqueue_batch(
+        &self,
+        request: Request<EnqueueBatchRequest>,
+    ) -> Result<Response<EnqueueBatchReply>, Status> {
+        trace!("{:?}", request);
+        let items = &request.get_ref().items;
+        let _dedup_guard = self.lock_dedup(items.iter().all(|x| x.dedup_key.is_empty()));
+        // duplicates keep their original id, in store or earlier in this batch
+        let mut known = HashMap::<&str, String>::new();
+        let mut fresh_keys = HashSet::<&str>::new();
+        let mut fresh = Vec::<&EnqueueRequest>::with_capacity(items.len());
+        for item in items {
+            let dedup_key = item.dedup_key.as_str();
+            if dedup_key.is_empty() {
+                fresh.push(item);
+                continue;
+            }
+            if known.contains_key(dedup_key) || fresh_keys.contains(dedup_key) {
+                continue;
+            }
+            match self.find_dedup(dedup_key)? {
+                Some(message_id) => {
+                    known.insert(dedup_key, message_id);
+                }
+                None => {
+                    fresh_keys.insert(dedup_key);
+                    fresh.push(item);
+                }
+            }
+        }
+        let first_seq = self.next_seq(fresh.len() as u64);
+        let mut task_items = Vec::<TaskItem>::with_capacity(fresh.len());
+        let mut batch = WriteBatch::default();
+        let mut dedups = Vec::<(String, u64)>::with_capacity(fresh_keys.len());
+        let mut plain_ids = Vec::<String>::with_capacity(fresh.len());
+        for (seq_no, item) in (first_seq..).zip(fresh) {
+            if item.dedup_key.is_empty() {
+                plain_ids.push(format!("{:}", seq_no));
+            } else {
+                known.insert(&item.dedup_key, format!("{:}", seq_no));
+                dedups.push((item.dedup_key.clone(), seq_no));
+            }
+            let expire_at = self.expire_at(item);
+            let (task_item, value_buf, index_buf, chunks) = encode_message(
+                seq_no,
+                item,
+                0,
+                expire_at,
+                &self.compression,
+                self.chunk_bytes,
+            );
+            {
+                let state = self.state.read().unwrap();
+                let message_id = &task_item.message_id;
+                state.put_message(&mut batch, message_id, value_buf, index_buf, chunks);
+            }
+            task_items.push(task_item);
+        }
+        self.save_dedup(&mut batch, dedups);
+        {
+            let state = self.state.read().unwrap();
+            if let Err(err) = self.committer.write(&state.msg_store, batch) {
+                return Err(Status::unknown(err.to_string()));
+            }
+        }
+        let mut plain_ids = plain_ids.into_iter();
+        let message_ids = items
+            .iter()
+            .map(|item| {
+                if item.dedup_key.is_empty() {
+                    plain_ids.next().unwrap()
+                } else {
+                    known[item.dedup_key.as_str()].clone()
+                }
+            })
+            .collect();
+        for task_item in task_items {
+            self.worker.add_task(task_item);
+        }
+        let reply = EnqueueBatchReply {
+            message_ids,
+            node_id: self.node_id.clone(),
+        };
+        Ok(Response::new(reply))
+    }
+
     fn enqueue_with_id(
         &self,
         cur_seq: u64,
         request: Request<EnqueueRequest>,
+        deliveries: u32,
+        expire_at: u64,
+        mut batch: WriteBatch,
     ) -> Result<EnqueueReply, Status> {
-        let message_id = cur_seq.to_be_bytes().to_vec();
-        let now = utils::timestamp();
-        let task_item = TaskItem {
-            priority: request.get_ref().priority,
-            timestamp: now + request.get_ref().deliver_after as u64,
-            message_id: message_id.clone(),
-        };
-        let mut value_buf = Vec::<u8>::with_capacity(200);
-        let _r = request.get_ref().encode(&mut value_buf);
-        let mut index_buf = Vec::<u8>::with_capacity(100);
-        let inner_index = InnerIndex {
-            priority: task_item.priority,
-            timestamp: task_item.timestamp,
-            message_id: message_id.clone(),
-        };
-        let _r = inner_index.encode(&mut index_buf);
+        let (task_item, value_buf, index_buf, chunks) = encode_message(
+            cur_seq,
+            request.get_ref(),
+            deliveries,
+            expire_at,
+            &self.compression,
+            self.chunk_bytes,
+        );
         {
             let state = self.state.read().unwrap();
-            match state.msg_store.set(&message_id, value_buf) {
-                Ok(_) => {}
-                Err(err) => {
-                    return Err(Status::unknown(err.to_string().clone()));
-                }
-            }
-            match state.index_store.set(&message_id, index_buf) {
+            let message_id = &task_item.message_id;
+            state.put_message(&mut batch, message_id, value_buf, index_buf, chunks);
+            match self.committer.write(&state.msg_store, batch) {
                 Ok(_) => {}
                 Err(err) => {
                     return Err(Status::unknown(err.to_string().clone()));
                 }
             }
+            // still under the lock, so the expire handler sees a nacked message is back
+            self.worker.add_task(task_item);
         }
-        self.worker.add_task(task_item);
         let reply = EnqueueReply {
             message_id: format!("{:}", cur_seq),
             node_id: self.node_id.clone(),
@@ -155,22 +680,130 @@ impl PriorityQueueSvc {
         Ok(reply)
     }
 
-    pub fn dequeue(
+    // the first of count new sequence numbers
+    fn next_seq(&self, count: u64) -> u64 {
+        let state = self.state.read().unwrap();
+        state.seq_no.fetch_add(count, Ordering::SeqCst) + 1
+    }
+
+    fn expire_at(&self, request: &EnqueueRequest) -> u64 {
+        let ttl_ms = if request.ttl_ms > 0 {
+            request.ttl_ms
+        } else {
+            self.options.default_ttl_ms
+        };
+        if ttl_ms == 0 {
+            return 0;
+        }
+        utils::timestamp() + ttl_ms as u64
+    }
+
+    fn lock_dedup(&self, skip: bool) -> Option<std::sync::MutexGuard<'_, u64>> {
+        if skip {
+            return None;
+        }
+        let mut pruned_at = self.dedup_lock.lock().unwrap();
+        let now = utils::timestamp();
+        if now > *pruned_at + DEDUP_PRUNE_INTERVAL {
+            *pruned_at = now;
+            self.prune_dedup(now);
+        }
+        Some(pruned_at)
+    }
+
+    fn find_dedup(&self, dedup_key: &str) -> Result<Option<String>, Status> {
+        if dedup_key.is_empty() {
+            return Ok(None);
+        }
+        let state = self.state.read().unwrap();
+        match state.index_store.get(&dedup_index_key(dedup_key)) {
+            Ok(entry_buf) => {
+                let entry = DedupEntry::decode(entry_buf.as_slice()).unwrap();
+                if entry.expire_at <= utils::timestamp() {
+                    return Ok(None);
+                }
+                Ok(Some(utils::msgid_to_str(&entry.message_id)))
+            }
+            Err(KvError::NotFound(_)) => Ok(None),
+            Err(err) => Err(Status::unknown(err.to_string())),
+        }
+    }
+
+    fn save_dedup(&self, batch: &mut WriteBatch, dedups: Vec<(String, u64)>) {
+        if dedups.is_empty() {
+            return;
+        }
+        let window = match self.options.dedup_window_ms {
+            0 => DEDUP_WINDOW,
+            window => window,
+        };
+        let expire_at = utils::timestamp() + window as u64;
+        let state = self.state.read().unwrap();
+        for (dedup_key, seq_no) in dedups {
+            let entry = DedupEntry {
+                message_id: seq_no.to_be_bytes().to_vec(),
+                expire_at,
+            };
+            let mut entry_buf = Vec::<u8>::with_capacity(30);
+            let _r = entry.encode(&mut entry_buf);
+            batch.put(&state.index_store, dedup_index_key(&dedup_key), entry_buf);
+        }
+    }
+
+    fn prune_dedup(&self, now: u64) {
+        let state = self.state.read().unwrap();
+        let mut expired = WriteBatch::default();
+        let mut total = 0;
+        let entries = match state.index_store.iter(KeyRange::prefix(DEDUP_PREFIX)) {
+            Ok(entries) => entries,
+            Err(err) => {
+                warn!("prune dedup keys failed: {:}", err);
+                return;
+            }
+        };
+        for item in entries {
+            let (k, v) = match item {
+                Ok(item) => item,
+                Err(err) => {
+                    warn!("prune dedup keys failed: {:}", err);
+                    return;
+                }
+            };
+            if DedupEntry::decode(v.as_slice()).unwrap().expire_at > now {
+                continue;
+            }
+            expired.delete(&state.index_store, k);
+            total += 1;
+            if total % 100 == 0 {
+                if let Err(err) = state.index_store.write(mem::take(&mut expired)) {
+                    warn!("prune dedup keys failed: {:}", err);
+                    return;
+                }
+            }
+        }
+        if !expired.is_empty() {
+            if let Err(err) = state.index_store.write(expired) {
+                warn!("prune dedup keys failed: {:}", err);
+                return;
+            }
+        }
+        trace!("{:} pruned {:} dedup keys", self.topic, total);
+    }
+
+    pub async fn dequeue(
         &self,
         request: Request<DequeueRequest>,
     ) -> Result<Response<DequeueReply>, Status> {
         trace!("{:?}", request);
         let task_items: Vec<TaskItem>;
-        task_items = self.worker.fetch_tasks(request.get_ref().count as u32);
-        if request.get_ref().lease_duration > 0 {
-            let retry_after = request.get_ref().lease_duration;
-            for task in &task_items {
-                let retry_task = task.delayed_copy(retry_after);
-                self.worker.add_task(retry_task);
-            }
+        let count = request.get_ref().count as u32;
+        if request.get_ref().wait_timeout_ms > 0 {
+            let wait_timeout = Duration::from_millis(request.get_ref().wait_timeout_ms as u64);
+            task_items = self.worker.wait_tasks(count, wait_timeout).await;
+        } else {
+            task_items = self.worker.fetch_tasks(count);
         }
-
-        let reply_items = self.fill_payload(task_items);
+        let reply_items = self.lease_payload(task_items, request.get_ref().lease_duration);
         if request.get_ref().lease_duration <= 0 {
             for item in &reply_items {
                 let state = self.state.read().unwrap();
@@ -181,21 +814,170 @@ impl PriorityQueueSvc {
         Ok(Response::new(reply))
     }
 
-    fn fill_payload(&self, task_items: Vec<TaskItem>) -> Vec<DataItem> {
+    pub async fn subscribe<S>(
+        &self,
+        lease_duration: i32,
+        mut credits: u32,
+        mut inbound: S,
+        outbound: mpsc::Sender<Result<DataItem, Status>>,
+    ) where
+        S: Stream<Item = Result<SubscribeRequest, Status>> + Unpin,
+    {
+        let poll_interval = Duration::from_millis(SUBSCRIBE_POLL_INTERVAL);
+        info!("subscriber joined {:}", self.topic);
+        'subscription: while !self.worker.is_stopped() {
+            if credits == 0 {
+                tokio::select! {
+                    grant = inbound.next() => match grant {
+                        Some(Ok(grant)) => credits = credits.saturating_add(grant.credits),
+                        _ => break,
+                    },
+                    _ = self.stopped() => break,
+                }
+                continue;
+            }
+            tokio::select! {
+                grant = inbound.next() => match grant {
+                    Some(Ok(grant)) => credits = credits.saturating_add(grant.credits),
+                    _ => break,
+                },
+                task_items = self.worker.wait_tasks(credits, poll_interval) => {
+                    for item in self.lease_payload(task_items, lease_duration) {
+                        credits -= 1;
+                        if !self.send_or_stop(&outbound, Ok(item)).await {
+                            break 'subscription;
+                        }
+                    }
+                }
+            }
+        }
+        info!("subscriber left {:}", self.topic);
+    }
+
+    fn lease_payload(&self, task_items: Vec<TaskItem>, lease_duration: i32) -> Vec<DataItem> {
+        let task_items = self.drop_exhausted(task_items);
+        if lease_duration > 0 {
+            let retry_tasks: Vec<TaskItem> = task_items
+                .iter()
+                .map(|task| {
+                    let mut retry_task = task.delayed_copy(lease_duration);
+                    retry_task.deliveries += 1;
+                    retry_task
+                })
+                .collect();
+            // a lease that is not saved is not handed out, its messages are ready again
+            if let Err(err) = self.save_leases(&retry_tasks) {
+                warn!("save leases of {:} failed: {:}", self.topic, err);
+                for task in task_items {
+                    self.worker.add_task(task);
+                }
+                return Vec::new();
+            }
+            for retry_task in retry_tasks {
+                self.worker.add_task(retry_task);
+            }
+        }
+        // an unleased message is removed right away, so it can not be fetched later
+        self.fill_payload(task_items, lease_duration <= 0)
+    }
+
+    // the lease deadline is saved as the index timestamp, so a restart keeps it in flight;
+    // a plain write unless the topic asks for durability
+    fn save_leases(&self, retry_tasks: &[TaskItem]) -> Result<(), KvError> {
+        if retry_tasks.is_empty() {
+            return Ok(());
+        }
+        let state = self.state.read().unwrap();
+        let mut batch = WriteBatch::default();
+        for task in retry_tasks {
+            batch.put(
+                &state.index_store,
+                task.message_id.clone(),
+                encode_index(task),
+            );
+        }
+        self.committer.write(&state.msg_store, batch)
+    }
+
+    fn drop_exhausted(&self, task_items: Vec<TaskItem>) -> Vec<TaskItem> {
+        let max_deliveries = self.options.max_deliveries;
+        if max_deliveries == 0 {
+            return task_items;
+        }
+        let (exhausted, task_items): (Vec<TaskItem>, Vec<TaskItem>) = task_items
+            .into_iter()
+            .partition(|task| task.deliveries >= max_deliveries);
+        for task in exhausted {
+            self.move_to_dead_letter(task);
+        }
+        task_items
+    }
+
+    fn move_to_dead_letter(&self, task: TaskItem) {
+        let state = self.state.read().unwrap();
+        let s_message_id = utils::msgid_to_str(&task.message_id);
+        let dead_letter_topic = &self.options.dead_letter_topic;
+        match forward_dead_letter(
+            &state,
+            &self.topic,
+            dead_letter_topic,
+            &self.dead_letter,
+            &task.message_id,
+        ) {
+            Some(Ok(reply)) => info!(
+                "move {:}:{:} to {:}:{:}",
+                self.topic, s_message_id, dead_letter_topic, reply.message_id
+            ),
+            // each retry counts as a delivery, past the retries the message is dropped
+            Some(Err(err))
+                if task.deliveries < self.options.max_deliveries + DEAD_LETTER_RETRIES =>
+            {
+                warn!(
+                    "dead letter {:}:{:} failed: {:}",
+                    self.topic, s_message_id, err
+                );
+                let mut retry_task = task.delayed_copy(DEAD_LETTER_RETRY);
+                retry_task.deliveries += 1;
+                self.worker.add_task(retry_task);
+                return;
+            }
+            Some(Err(err)) => warn!(
+                "drop {:}:{:}, dead letter failed {:} times: {:}",
+                self.topic, s_message_id, DEAD_LETTER_RETRIES, err
+            ),
+            None => warn!(
+                "drop {:}:{:} after {:} deliveries",
+                self.topic, s_message_id, task.deliveries
+            ),
+        }
+        self.remove_msg(&state, task.message_id);
+    }
+
+    fn fill_payload(&self, task_items: Vec<TaskItem>, inline_chunks: bool) -> Vec<DataItem> {
         let state = self.state.read().unwrap();
         let reply_items: Vec<DataItem> = task_items
             .par_iter()
             .filter_map(|ti| {
-                let value_buf = state.msg_store.get(&ti.message_id);
+                let value_buf = state.get_value(&ti.message_id);
                 match value_buf {
                     Ok(value_buf) => {
                         let s_message_id = utils::msgid_to_str(&ti.message_id);
-                        let req = EnqueueRequest::decode(value_buf.as_slice()).unwrap();
+                        let mut req = decode_request(value_buf);
+                        let mut payload_chunks = 0;
+                        if req.payload.is_empty() {
+                            payload_chunks = state.chunk_count(&ti.message_id);
+                        }
+                        if inline_chunks && payload_chunks > 0 {
+                            req = state.load_message(&ti.message_id).ok()?;
+                            payload_chunks = 0;
+                        }
                         Some(DataItem {
                             message_id: s_message_id,
                             payload: req.payload,
                             meta: req.meta,
                             priority: req.priority,
+                            deliveries: ti.deliveries + 1,
+                            payload_chunks,
                         })
                     }
                     Err(_) => None,
@@ -205,69 +987,374 @@ impl PriorityQueueSvc {
         reply_items
     }
 
-    pub fn get_stats(&self) -> TopicStats {
-        let stats = self.worker.stats();
-        let stats = TopicStats {
-            topic: self.topic.clone(),
-            ready_size: stats.ready_size,
-            delayed_size: stats.delayed_size,
+    pub async fn fetch_payload(
+        &self,
+        message_id: u64,
+        outbound: mpsc::Sender<Result<PayloadChunk, Status>>,
+    ) {
+        let message_id = message_id.to_be_bytes().to_vec();
+        if !self.worker.is_leased(&message_id) {
+            let not_leased = Err(Status::failed_precondition("message not leased"));
+            self.send_or_stop(&outbound, not_leased).await;
+            return;
+        }
+        // an inline payload goes out as a single chunk
+        let loaded = {
+            let state = self.state.read().unwrap();
+            state
+                .get_value(&message_id)
+                .map(|value_buf| match state.chunk_count(&message_id) {
+                    0 => (Some(decode_request(value_buf).payload), 1),
+                    total => (None, total),
+                })
         };
-        stats
+        let total = match loaded {
+            Ok((Some(data), total)) => {
+                let chunk = PayloadChunk {
+                    data,
+                    index: 0,
+                    total,
+                };
+                self.send_or_stop(&outbound, Ok(chunk)).await;
+                return;
+            }
+            Ok((None, total)) => total,
+            Err(_) => {
+                let not_found = Err(Status::not_found("message not found"));
+                self.send_or_stop(&outbound, not_found).await;
+                return;
+            }
+        };
+        for chunk_no in 0..total {
+            // a removed topic waits for the stream to end before dropping its storage
+            if self.worker.is_stopped() {
+                let _r = outbound.try_send(Err(Status::aborted("topic stopped")));
+                break;
+            }
+            // the lock is not held across sends, so the count is read again with every chunk:
+            // an ack meanwhile ends the stream, a payload stored again aborts it
+            let chunk = {
+                let state = self.state.read().unwrap();
+                match state.chunk_meta(&message_id) {
+                    (0, _) => Err(Status::not_found("message removed")),
+                    (cur_total, _) if cur_total != total => Err(Status::aborted("payload changed")),
+                    (_, tagged) => match state.load_chunk(&message_id, chunk_no, tagged) {
+                        Ok(data) => Ok(PayloadChunk {
+                            data,
+                            index: chunk_no,
+                            total,
+                        }),
+                        Err(_) => Err(Status::not_found("message removed")),
+                    },
+                }
+            };
+            let failed = chunk.is_err();
+            if !self.send_or_stop(&outbound, chunk).await || failed {
+                break;
+            }
+        }
     }
 
-    pub fn ack(&self, request: Request<AckRequest>) -> Result<Response<AckReply>, Status> {
-        trace!("{:?}", request);
-        let message_id = utils::msgid_to_raw(&request.get_ref().message_id);
-        if !self.worker.cancel_task(&message_id) {
-            return Err(Status::not_found("no lease found"));
+    // resolves once the topic is stopped, also when it stopped before the call
+    async fn stopped(&self) {
+        let notified = self.stop_notifier.notified();
+        tokio::pin!(notified);
+        notified.as_mut().enable();
+        if !self.worker.is_stopped() {
+            notified.await;
         }
-        {
-            let state = self.state.read().unwrap();
-            if let Some(value) = self.remove_msg(&state, message_id) {
-                return value;
-            }
+    }
+
+    // false when the client is gone, or the topic stopped while the client was not reading
+    async fn send_or_stop<T>(&self, outbound: &mpsc::Sender<T>, item: T) -> bool {
+        tokio::select! {
+            sent = outbound.send(item) => sent.is_ok(),
+            _ = self.stopped() => false,
         }
-        let reply = AckReply {};
-        Ok(Response::new(reply))
     }
 
-    pub fn nack(&self, request: Request<NackRequest>) -> Result<Response<NackReply>, Status> {
+    pub fn schedule_recurring(
+        &self,
+        request: Request<ScheduleRecurringRequest>,
+    ) -> Result<Response<ScheduleRecurringReply>, Status> {
         trace!("{:?}", request);
-        let message_id = utils::msgid_to_raw(&request.get_ref().message_id);
-        let _canceled = self.worker.cancel_task(&message_id);
-        let old_payload: Vec<u8>;
-        let old_priority: i32;
-        let old_meta: String;
-        let new_meta: String;
-        {
-            let state = self.state.read().unwrap();
-            let value_buf = state.msg_store.get(&message_id);
-            match value_buf {
-                Ok(value_buf) => {
-                    let raw_req = EnqueueRequest::decode(value_buf.as_slice()).unwrap();
-                    old_payload = raw_req.payload;
-                    old_priority = raw_req.priority;
-                    old_meta = raw_req.meta;
-                }
-                Err(err) => {
-                    return Err(Status::not_found(err.to_string()));
-                }
+        let request = request.into_inner();
+        let mut template = match request.template {
+            Some(template) => template,
+            None => return Err(Status::invalid_argument("template is required")),
+        };
+        template.topic = self.topic.clone();
+        if request.cron.is_empty() && request.interval_ms == 0 {
+            return Err(Status::invalid_argument("cron or interval_ms is required"));
+        }
+        if let Err(err) = CronSchedule::from_str(&request.cron) {
+            if !request.cron.is_empty() {
+                return Err(Status::invalid_argument(err.to_string()));
             }
         }
-        if request.get_ref().meta.len() > 0 {
-            new_meta = request.get_ref().meta.clone();
-        } else {
-            new_meta = old_meta;
+        let mut schedules = self.schedules.lock().unwrap();
+        let schedule_id = self.last_schedule_id.load(Ordering::SeqCst) + 1;
+        let mut schedule = Schedule {
+            schedule_id: format!("{:}", schedule_id),
+            cron: request.cron,
+            interval_ms: request.interval_ms,
+            template: Some(template),
+            next_fire_at: 0,
+        };
+        schedule.next_fire_at = match next_fire_at(&schedule, utils::timestamp()) {
+            Some(fire_at) => fire_at,
+            None => return Err(Status::invalid_argument("schedule never fires")),
+        };
+        if let Err(err) = self.save_schedule(schedule_id, &schedule) {
+            return Err(Status::unknown(err.to_string()));
         }
-        let enq_again_request = tonic::Request::new(EnqueueRequest {
-            topic: request.get_ref().topic.clone(),
-            payload: old_payload,
-            meta: new_meta,
-            priority: old_priority,
-            deliver_after: request.get_ref().deliver_after,
-        });
+        self.last_schedule_id.store(schedule_id, Ordering::SeqCst);
+        let reply = ScheduleRecurringReply {
+            schedule_id: schedule.schedule_id.clone(),
+            next_fire_at: schedule.next_fire_at,
+        };
+        schedules.insert(schedule_id, schedule);
+        self.schedule_notifier.notify_one();
+        Ok(Response::new(reply))
+    }
+
+    pub fn list_schedules(
+        &self,
+        request: Request<ListSchedulesRequest>,
+    ) -> Result<Response<ListSchedulesReply>, Status> {
+        trace!("{:?}", request);
+        let schedules = self.schedules.lock().unwrap();
+        let mut schedules: Vec<Schedule> = schedules.values().cloned().collect();
+        schedules.sort_by_key(|x| x.schedule_id.parse::<u64>().unwrap());
+        let reply = ListSchedulesReply { schedules };
+        Ok(Response::new(reply))
+    }
+
+    pub fn delete_schedule(
+        &self,
+        request: Request<DeleteScheduleRequest>,
+    ) -> Result<Response<DeleteScheduleReply>, Status> {
+        trace!("{:?}", request);
+        let schedule_id = match request.get_ref().schedule_id.parse::<u64>() {
+            Ok(schedule_id) => schedule_id,
+            Err(_) => return Err(Status::invalid_argument("invalid schedule id")),
+        };
+        let mut schedules = self.schedules.lock().unwrap();
+        if !schedules.contains_key(&schedule_id) {
+            return Err(Status::not_found("schedule not found"));
+        }
+        {
+            let state = self.state.read().unwrap();
+            if let Err(err) = state.index_store.remove(&schedule_index_key(schedule_id)) {
+                return Err(Status::unknown(err.to_string()));
+            }
+        }
+        schedules.remove(&schedule_id);
+        let reply = DeleteScheduleReply {};
+        Ok(Response::new(reply))
+    }
+
+    pub fn start_schedules(self: &Arc<Self>) {
+        let svc = Arc::downgrade(self);
+        let notifier = self.schedule_notifier.clone();
+        tokio::task::spawn(async move {
+            loop {
+                let wake_at = match svc.upgrade() {
+                    Some(svc) if !svc.worker.is_stopped() => svc.fire_schedules(),
+                    _ => break,
+                };
+                let wait = wake_at.saturating_sub(utils::timestamp());
+                let wait = Duration::from_millis(wait.min(SCHEDULE_IDLE));
+                let _ = time::timeout(wait, notifier.notified()).await;
+            }
+        });
+    }
+
+    fn fire_schedules(&self) -> u64 {
+        let now = utils::timestamp();
+        let mut wake_at = now + SCHEDULE_IDLE;
+        // advanced under the lock, written after it is released
+        let mut fired = Vec::<(u64, EnqueueRequest, Option<Vec<u8>>)>::new();
+        {
+            let mut schedules = self.schedules.lock().unwrap();
+            let mut finished = Vec::<u64>::new();
+            for (schedule_id, schedule) in schedules.iter_mut() {
+                if schedule.next_fire_at <= now {
+                    let request = schedule.template.clone().unwrap_or_default();
+                    match next_fire_at(schedule, now) {
+                        Some(fire_at) => {
+                            schedule.next_fire_at = fire_at;
+                            let mut value_buf = Vec::<u8>::with_capacity(200);
+                            let _r = schedule.encode(&mut value_buf);
+                            fired.push((*schedule_id, request, Some(value_buf)));
+                        }
+                        None => {
+                            fired.push((*schedule_id, request, None));
+                            finished.push(*schedule_id);
+                            continue;
+                        }
+                    }
+                }
+                wake_at = wake_at.min(schedule.next_fire_at);
+            }
+            for schedule_id in finished {
+                info!("schedule {:}:{:} finished", self.topic, schedule_id);
+                schedules.remove(&schedule_id);
+            }
+        }
+        let mut advanced = Vec::<u64>::with_capacity(fired.len());
+        for (schedule_id, request, value_buf) in fired {
+            let cur_seq = self.next_seq(1);
+            let expire_at = self.expire_at(&request);
+            // the message and the advanced schedule commit together
+            let mut batch = WriteBatch::default();
+            {
+                let state = self.state.read().unwrap();
+                let schedule_key = schedule_index_key(schedule_id);
+                match value_buf {
+                    Some(value_buf) => {
+                        batch.put(&state.index_store, schedule_key, value_buf);
+                        advanced.push(schedule_id);
+                    }
+                    None => batch.delete(&state.index_store, schedule_key),
+                }
+            }
+            let request = Request::new(request);
+            match self.enqueue_with_id(cur_seq, request, 0, expire_at, batch) {
+                Ok(reply) => trace!(
+                    "schedule {:}:{:} fired {:}",
+                    self.topic,
+                    schedule_id,
+                    reply.message_id
+                ),
+                Err(err) => warn!("schedule {:}:{:} failed: {:}", self.topic, schedule_id, err),
+            }
+        }
+        // a schedule deleted while it fired must not come back on restart
+        let schedules = self.schedules.lock().unwrap();
+        let state = self.state.read().unwrap();
+        for schedule_id in advanced {
+            if !schedules.contains_key(&schedule_id) {
+                let _r = state.index_store.remove(&schedule_index_key(schedule_id));
+            }
+        }
+        wake_at
+    }
+
+    // a new schedule, saved with its id as the last one handed out
+    fn save_schedule(&self, schedule_id: u64, schedule: &Schedule) -> Result<(), KvError> {
+        let mut value_buf = Vec::<u8>::with_capacity(200);
+        let _r = schedule.encode(&mut value_buf);
+        let state = self.state.read().unwrap();
+        let mut batch = WriteBatch::default();
+        batch.put(
+            &state.index_store,
+            schedule_index_key(schedule_id),
+            value_buf,
+        );
+        batch.put(
+            &state.index_store,
+            schedule_last_id_key(),
+            schedule_id.to_be_bytes().to_vec(),
+        );
+        state.msg_store.write(batch)
+    }
+
+    pub fn get_stats(&self) -> TopicStats {
+        let stats = self.worker.stats();
+        let stats = TopicStats {
+            topic: self.topic.clone(),
+            ready_size: stats.ready_size,
+            delayed_size: stats.delayed_size,
+            expired: stats.expired,
+            throttled: stats.throttled,
+        };
+        stats
+    }
+
+    pub fn ack(&self, request: Request<AckRequest>) -> Result<Response<AckReply>, Status> {
+        trace!("{:?}", request);
+        let message_id = utils::msgid_to_raw(&request.get_ref().message_id);
+        if !self.worker.cancel_task(&message_id) {
+            return Err(Status::not_found("no lease found"));
+        }
+        {
+            let state = self.state.read().unwrap();
+            if let Some(value) = self.remove_msg(&state, message_id) {
+                return value;
+            }
+        }
+        let reply = AckReply {};
+        Ok(Response::new(reply))
+    }
+
+    pub fn extend_lease(
+        &self,
+        request: Request<ExtendLeaseRequest>,
+    ) -> Result<Response<ExtendLeaseReply>, Status> {
+        trace!("{:?}", request);
+        if request.get_ref().lease_duration <= 0 {
+            return Err(Status::invalid_argument("lease_duration must be positive"));
+        }
+        let message_id = utils::msgid_to_raw(&request.get_ref().message_id);
+        let deadline = utils::timestamp() + request.get_ref().lease_duration as u64;
+        let state = self.state.read().unwrap();
+        let save = |task: &TaskItem| {
+            let mut batch = WriteBatch::default();
+            batch.put(&state.index_store, message_id.clone(), encode_index(task));
+            self.committer
+                .write(&state.msg_store, batch)
+                .map_err(|err| err.to_string())
+        };
+        let extended =
+            self.worker
+                .extend_task(&message_id, request.get_ref().deliveries, deadline, save);
+        match extended {
+            Ok(_) => {}
+            Err(ExtendError::Expired) => {
+                return Err(Status::failed_precondition("lease expired"));
+            }
+            Err(ExtendError::Redelivered) => {
+                return Err(Status::failed_precondition("message redelivered"));
+            }
+            Err(ExtendError::Unsaved(err)) => return Err(Status::unknown(err)),
+        }
+        let reply = ExtendLeaseReply {};
+        Ok(Response::new(reply))
+    }
+
+    pub fn nack(&self, request: Request<NackRequest>) -> Result<Response<NackReply>, Status> {
+        trace!("{:?}", request);
+        let message_id = utils::msgid_to_raw(&request.get_ref().message_id);
+        let _canceled = self.worker.cancel_task(&message_id);
+        let enq_again_request: EnqueueRequest;
+        let deliveries: u32;
+        let expire_at: u64;
+        {
+            let state = self.state.read().unwrap();
+            let nack = request.get_ref();
+            match self.load_for_nack(
+                &state,
+                &message_id,
+                &nack.topic,
+                &nack.meta,
+                nack.deliver_after,
+            ) {
+                Ok((enq_request, old_deliveries, old_expire_at)) => {
+                    enq_again_request = enq_request;
+                    deliveries = old_deliveries;
+                    expire_at = old_expire_at;
+                }
+                Err(err) => {
+                    self.worker.finish_task(&message_id);
+                    return Err(err);
+                }
+            }
+        }
+        let enq_again_request = tonic::Request::new(enq_again_request);
         let seq_no = utils::msgid_to_u64(&message_id);
-        let enq_ret = self.enqueue_with_id(seq_no, enq_again_request);
+        let batch = WriteBatch::default();
+        let enq_ret = self.enqueue_with_id(seq_no, enq_again_request, deliveries, expire_at, batch);
         match enq_ret {
             Ok(_) => {
                 let reply = NackReply {};
@@ -279,32 +1366,371 @@ impl PriorityQueueSvc {
         }
     }
 
+    pub fn ack_batch(
+        &self,
+        request: Request<AckBatchRequest>,
+    ) -> Result<Response<AckBatchReply>, Status> {
+        trace!("{:?}", request);
+        let (message_ids, mut results) = parse_message_ids(&request.get_ref().message_ids);
+        let canceled = self.worker.cancel_tasks(&message_ids);
+        let mut acked = Vec::<usize>::with_capacity(message_ids.len());
+        for (i, ok) in canceled.into_iter().enumerate() {
+            if !results[i].error.is_empty() {
+                continue;
+            }
+            if ok {
+                acked.push(i);
+            } else {
+                results[i].error = "no lease found".into();
+            }
+        }
+        let keys: Vec<Vec<u8>> = acked.iter().map(|i| message_ids[*i].clone()).collect();
+        self.worker.finish_tasks(&keys);
+        let removed = {
+            let state = self.state.read().unwrap();
+            remove_msgs(&state, keys)
+        };
+        for i in acked {
+            match &removed {
+                Ok(_) => results[i].ok = true,
+                Err(err) => results[i].error = err.to_string(),
+            }
+        }
+        let reply = AckBatchReply { results };
+        Ok(Response::new(reply))
+    }
+
+    pub fn nack_batch(
+        &self,
+        request: Request<NackBatchRequest>,
+    ) -> Result<Response<NackBatchReply>, Status> {
+        trace!("{:?}", request);
+        let nack = request.get_ref();
+        let (message_ids, mut results) = parse_message_ids(&nack.message_ids);
+        let _canceled = self.worker.cancel_tasks(&message_ids);
+        let mut nacked = Vec::<usize>::with_capacity(message_ids.len());
+        let mut task_items = Vec::<TaskItem>::with_capacity(message_ids.len());
+        {
+            let state = self.state.read().unwrap();
+            let mut batch = WriteBatch::default();
+            for (i, message_id) in message_ids.iter().enumerate() {
+                if !results[i].error.is_empty() {
+                    continue;
+                }
+                let loaded = self.load_for_nack(
+                    &state,
+                    message_id,
+                    &nack.topic,
+                    &nack.meta,
+                    nack.deliver_after,
+                );
+                match loaded {
+                    Ok((enq_request, deliveries, expire_at)) => {
+                        let seq_no = utils::msgid_to_u64(message_id);
+                        let (task_item, value_buf, index_buf, chunks) = encode_message(
+                            seq_no,
+                            &enq_request,
+                            deliveries,
+                            expire_at,
+                            &self.compression,
+                            self.chunk_bytes,
+                        );
+                        state.put_message(&mut batch, message_id, value_buf, index_buf, chunks);
+                        task_items.push(task_item);
+                        nacked.push(i);
+                    }
+                    Err(err) => {
+                        results[i].error = err.message().into();
+                        self.worker.finish_task(message_id);
+                    }
+                }
+            }
+            if let Err(err) = self.committer.write(&state.msg_store, batch) {
+                for i in nacked {
+                    results[i].error = err.to_string();
+                    self.worker.finish_task(&message_ids[i]);
+                }
+                let reply = NackBatchReply { results };
+                return Ok(Response::new(reply));
+            }
+        }
+        for i in nacked {
+            results[i].ok = true;
+        }
+        for task_item in task_items {
+            self.worker.add_task(task_item);
+        }
+        let reply = NackBatchReply { results };
+        Ok(Response::new(reply))
+    }
+
+    fn load_for_nack(
+        &self,
+        state: &std::sync::RwLockReadGuard<SharedState>,
+        message_id: &Vec<u8>,
+        topic: &str,
+        meta: &str,
+        deliver_after: u32,
+    ) -> Result<(EnqueueRequest, u32, u64), Status> {
+        let (deliveries, expire_at) = match state.index_store.get(message_id) {
+            Ok(index_buf) => {
+                let inner_index = InnerIndex::decode(index_buf.as_slice()).unwrap();
+                (inner_index.deliveries, inner_index.expire_at)
+            }
+            Err(_) => (0, 0),
+        };
+        let value_buf = match state.get_value(message_id) {
+            Ok(value_buf) => value_buf,
+            Err(err) => {
+                return Err(Status::not_found(err.to_string()));
+            }
+        };
+        let raw_req = decode_request(value_buf);
+        let new_meta = if !meta.is_empty() {
+            meta.into()
+        } else {
+            raw_req.meta
+        };
+        let enq_request = EnqueueRequest {
+            topic: topic.into(),
+            payload: raw_req.payload,
+            meta: new_meta,
+            priority: raw_req.priority,
+            deliver_after,
+            ttl_ms: 0,
+            dedup_key: "".into(),
+            group_key: raw_req.group_key,
+        };
+        Ok((enq_request, deliveries, expire_at))
+    }
+
     fn remove_msg(
         &self,
         state: &std::sync::RwLockReadGuard<SharedState>,
         message_id: Vec<u8>,
     ) -> Option<Result<Response<AckReply>, Status>> {
-        match state.index_store.remove(&message_id) {
+        self.worker.finish_task(&message_id);
+        let mut batch = WriteBatch::default();
+        state.delete_message(&mut batch, message_id);
+        match state.msg_store.write(batch) {
             Ok(_) => {}
             Err(err) => {
                 return Some(Err(Status::unknown(err.to_string().clone())));
             }
         }
-        let msgid = utils::msgid_to_u64(&message_id);
-        if msgid == state.seq_no {
-            return None;
+        None
+    }
+
+    // holds the state write lock, so no half written batch is taken for an orphan
+    pub fn check(
+        &self,
+        request: Request<CheckTopicRequest>,
+    ) -> Result<Response<CheckTopicReply>, Status> {
+        let repair = fsck::parse_repair(&request.get_ref().repair).unwrap_or(Repair::NONE);
+        if repair == Repair::QUARANTINE && self.dead_letter.is_none() {
+            return Err(Status::failed_precondition("no topic to quarantine into"));
         }
-        match state.msg_store.remove(&message_id) {
-            Ok(_) => {}
+        let state = self.state.write().unwrap();
+        let findings = match fsck::scan(&state.msg_store, &state.index_store, &state.chunk_store) {
+            Ok(findings) => findings,
+            Err(err) => return Err(Status::unknown(err.to_string())),
+        };
+        let mut reply = CheckTopicReply {
+            messages: findings.messages,
+            orphan_payloads: findings
+                .orphan_payloads
+                .iter()
+                .map(|(message_id, _)| utils::msgid_to_str(message_id))
+                .collect(),
+            broken_payloads: findings
+                .broken_payloads
+                .iter()
+                .map(utils::msgid_to_str)
+                .collect(),
+            orphan_indexes: findings
+                .orphan_indexes
+                .iter()
+                .map(utils::msgid_to_str)
+                .collect(),
+            broken_indexes: findings
+                .broken_indexes
+                .iter()
+                .map(utils::msgid_to_str)
+                .collect(),
+            orphan_chunks: findings.orphan_chunks.len() as u32,
+            repaired: 0,
+        };
+        if repair != Repair::NONE {
+            let quarantine_topic = &request.get_ref().quarantine_topic;
+            reply.repaired = self.repair(&state, findings, repair, quarantine_topic)?;
+        }
+        info!(
+            "checked {:} messages of {:}, repaired {:}",
+            reply.messages, self.topic, reply.repaired
+        );
+        Ok(Response::new(reply))
+    }
+
+    // quarantined messages are enqueued before the deletes are written, so a failed
+    // write leaves them in both topics rather than in neither
+    fn repair(
+        &self,
+        state: &SharedState,
+        findings: Findings,
+        repair: Repair,
+        quarantine_topic: &str,
+    ) -> Result<u64, Status> {
+        let mut batch = WriteBatch::default();
+        let mut dropped = Vec::<Vec<u8>>::new();
+        let mut task_items = Vec::<TaskItem>::new();
+        let mut repaired = 0 as u64;
+        // nothing is left to deliver for these
+        for message_id in findings.orphan_indexes {
+            batch.delete(&state.index_store, message_id.clone());
+            dropped.push(message_id);
+            repaired += 1;
+        }
+        for key in findings.orphan_chunks {
+            batch.delete(&state.chunk_store, key);
+            repaired += 1;
+        }
+        for message_id in findings.broken_indexes {
+            batch.delete(&state.index_store, message_id);
+        }
+        for (message_id, request) in findings.orphan_payloads {
+            match repair {
+                Repair::REINDEX => {
+                    let task_item = TaskItem {
+                        priority: request.priority,
+                        timestamp: utils::timestamp(),
+                        message_id: message_id.clone(),
+                        deliveries: 0,
+                        expire_at: self.expire_at(&request),
+                        group_key: request.group_key,
+                    };
+                    batch.put(&state.index_store, message_id, encode_index(&task_item));
+                    task_items.push(task_item);
+                }
+                Repair::QUARANTINE => {
+                    match forward_dead_letter(
+                        state,
+                        &self.topic,
+                        quarantine_topic,
+                        &self.dead_letter,
+                        &message_id,
+                    ) {
+                        Some(Ok(_)) => state.delete_message(&mut batch, message_id),
+                        Some(Err(err)) => {
+                            warn!("quarantine {:} failed: {:}", self.topic, err);
+                            continue;
+                        }
+                        None => continue,
+                    }
+                }
+                _ => state.delete_message(&mut batch, message_id),
+            }
+            repaired += 1;
+        }
+        for message_id in findings.broken_payloads {
+            dropped.push(message_id.clone());
+            match repair {
+                Repair::REINDEX => {
+                    batch.delete(&state.index_store, message_id);
+                    continue;
+                }
+                Repair::QUARANTINE => {
+                    if !self.quarantine_raw(state, &message_id, quarantine_topic) {
+                        continue;
+                    }
+                    state.delete_message(&mut batch, message_id);
+                }
+                _ => state.delete_message(&mut batch, message_id),
+            }
+            repaired += 1;
+        }
+        if let Err(err) = state.msg_store.write(batch) {
+            return Err(Status::unknown(err.to_string()));
+        }
+        self.worker.drop_tasks(&dropped);
+        for task_item in task_items {
+            self.worker.add_task(task_item);
+        }
+        Ok(repaired)
+    }
+
+    // an unreadable value is moved as the payload of a new message
+    fn quarantine_raw(&self, state: &SharedState, message_id: &Vec<u8>, topic: &str) -> bool {
+        let dead_letter = match &self.dead_letter {
+            Some(dead_letter) => dead_letter,
+            None => return false,
+        };
+        let value_buf = match state.msg_store.get(message_id) {
+            Ok(value_buf) => value_buf,
+            Err(_) => return false,
+        };
+        let req = EnqueueRequest {
+            topic: topic.into(),
+            payload: value_buf,
+            meta: format!("{:}:{:}", self.topic, utils::msgid_to_str(message_id)),
+            ..Default::default()
+        };
+        match dead_letter(req) {
+            Ok(_) => true,
             Err(err) => {
-                return Some(Err(Status::unknown(err.to_string().clone())));
+                warn!("quarantine {:} failed: {:}", self.topic, err);
+                false
             }
         }
-        None
+    }
+
+    // writes go on meanwhile: a rocksdb checkpoint is consistent by itself, a sled export
+    // or a copy may be cut between a payload and its index. Every message up to the
+    // returned seq_no was written before the copy started, so only later ones can miss
+    // their index and are reindexed on restore
+    pub fn backup(&self, dir: &str) -> Result<(DbKind, u64), KvError> {
+        let seq_no = self.state.write().unwrap().seq_no.load(Ordering::SeqCst);
+        let state = self.state.read().unwrap();
+        if let Some(db_kind) = state.msg_store.checkpoint(dir)? {
+            return Ok((db_kind, seq_no));
+        }
+        let copy = kv::new_kvstore(DbKind::ROCKSDB, dir.into())?;
+        let total = copy_topic(&state.msg_store, &copy)?;
+        info!("copied {:} keys of {:} to {:}", total, self.topic, dir);
+        Ok((DbKind::ROCKSDB, seq_no))
+    }
+
+    // a restored copy may hold payloads above the seq_no of its backup that missed their
+    // index; older orphans were orphans in the topic already and are left to check
+    pub fn reindex_restored(&self, seq_no: u64) -> Result<u64, Status> {
+        let state = self.state.write().unwrap();
+        let mut findings =
+            match fsck::scan(&state.msg_store, &state.index_store, &state.chunk_store) {
+                Ok(findings) => findings,
+                Err(err) => return Err(Status::unknown(err.to_string())),
+            };
+        findings
+            .orphan_payloads
+            .retain(|(message_id, _)| utils::msgid_to_u64(message_id) > seq_no);
+        self.repair(&state, findings, Repair::REINDEX, "")
+    }
+
+    pub fn set_rate_limit(&self, rate_limit: u32, rate_burst: u32) {
+        info!(
+            "rate limit of {:} set to {:}/s, burst {:}",
+            self.topic, rate_limit, rate_burst
+        );
+        self.worker.set_rate_limit(rate_limit, rate_burst);
     }
 
     pub async fn stop(&self) {
         self.worker.stop().await;
+        self.stop_notifier.notify_waiters();
+    }
+
+    // subscribe and fetch_payload tasks end once the topic is stopped, its storage can be
+    // dropped after they and the expire handlers let go of it
+    pub fn releasing(&self) -> Releasing {
+        Releasing(Arc::downgrade(&self.state))
     }
 }
 
@@ -312,22 +1738,28 @@ impl PriorityQueueSvc {
 mod tests {
     use super::super::super::storage::kv;
     use super::*;
+    use std::sync::atomic::AtomicBool;
     use temp_dir::TempDir;
-    use tokio::time::{sleep, Duration};
+    use tokio::time::sleep;
     #[tokio::test]
     async fn basic_put_get() {
-        let tmp_dir = TempDir::new().unwrap();
-        let sub_dir = tmp_dir.path().to_str().unwrap().into();
-        let index_dir = format!("{}_index", sub_dir);
-        let msg_store = kv::new_kvstore(kv::DbKind::SLED, sub_dir).unwrap();
-        let index_store = kv::new_kvstore(kv::DbKind::SLED, index_dir).unwrap();
-        let service = make_one_queue(msg_store, index_store, &"test_node".into(), &"root".into());
+        let msg_store = kv::new_kvstore(kv::DbKind::MEMORY, "root".into()).unwrap();
+        let service = make_one_queue(
+            msg_store,
+            &"test_node".into(),
+            &"root".into(),
+            TopicOptions::default(),
+            None,
+        );
         let r1 = tonic::Request::new(EnqueueRequest {
             topic: "test".into(),
             payload: vec![1, 2, 3],
             meta: "r1".into(),
             priority: 1,
             deliver_after: 0,
+            ttl_ms: 0,
+            dedup_key: "".into(),
+            group_key: "".into(),
         });
         let r2 = tonic::Request::new(EnqueueRequest {
             topic: "test".into(),
@@ -335,6 +1767,9 @@ mod tests {
             meta: "r2".into(),
             priority: 0,
             deliver_after: 0,
+            ttl_ms: 0,
+            dedup_key: "".into(),
+            group_key: "".into(),
         });
         let r3 = tonic::Request::new(EnqueueRequest {
             topic: "test".into(),
@@ -342,6 +1777,9 @@ mod tests {
             meta: "r3".into(),
             priority: 1,
             deliver_after: 0,
+            ttl_ms: 0,
+            dedup_key: "".into(),
+            group_key: "".into(),
         });
         let r4 = tonic::Request::new(EnqueueRequest {
             topic: "test".into(),
@@ -349,6 +1787,9 @@ mod tests {
             meta: "r4".into(),
             priority: -1,
             deliver_after: 2,
+            ttl_ms: 0,
+            dedup_key: "".into(),
+            group_key: "".into(),
         });
         let result1 = service.enqueue(r1);
         assert!(result1.is_ok());
@@ -363,7 +1804,9 @@ mod tests {
                 topic: "test".into(),
                 count: 4,
                 lease_duration: 0,
+                wait_timeout_ms: 0,
             }))
+            .await
             .unwrap();
         let mut n = 0 as i32;
         for x in &pops.get_ref().items {
@@ -388,7 +1831,9 @@ mod tests {
                 topic: "test".into(),
                 count: 1,
                 lease_duration: 5,
+                wait_timeout_ms: 0,
             }))
+            .await
             .unwrap();
         assert_eq!(pops.get_ref().items[0].meta, "r4");
         println!("sleep 5 seconds");
@@ -398,7 +1843,9 @@ mod tests {
                 topic: "test".into(),
                 count: 1,
                 lease_duration: 0,
+                wait_timeout_ms: 0,
             }))
+            .await
             .unwrap();
         assert_eq!(pops.get_ref().items[0].meta, "r4");
         println!("sleep 5 seconds");
@@ -408,9 +1855,1735 @@ mod tests {
                 topic: "test".into(),
                 count: 1,
                 lease_duration: 0,
+                wait_timeout_ms: 0,
+            }))
+            .await
+            .unwrap();
+        assert_eq!(pops.get_ref().items.len(), 0);
+        service.stop().await;
+    }
+
+    #[tokio::test]
+    async fn dequeue_wait_timeout() {
+        let msg_store = kv::new_kvstore(kv::DbKind::MEMORY, "root".into()).unwrap();
+        let service = make_one_queue(
+            msg_store,
+            &"test_node".into(),
+            &"root".into(),
+            TopicOptions::default(),
+            None,
+        );
+        let pops = service
+            .dequeue(tonic::Request::new(DequeueRequest {
+                topic: "test".into(),
+                count: 1,
+                lease_duration: 0,
+                wait_timeout_ms: 100,
+            }))
+            .await
+            .unwrap();
+        assert_eq!(pops.get_ref().items.len(), 0);
+        let r1 = tonic::Request::new(EnqueueRequest {
+            topic: "test".into(),
+            payload: vec![1, 2, 3],
+            meta: "r1".into(),
+            priority: 0,
+            deliver_after: 200,
+            ttl_ms: 0,
+            dedup_key: "".into(),
+            group_key: "".into(),
+        });
+        assert!(service.enqueue(r1).is_ok());
+        let pops = service
+            .dequeue(tonic::Request::new(DequeueRequest {
+                topic: "test".into(),
+                count: 1,
+                lease_duration: 0,
+                wait_timeout_ms: 2000,
+            }))
+            .await
+            .unwrap();
+        assert_eq!(pops.get_ref().items[0].meta, "r1");
+        service.stop().await;
+    }
+
+    #[tokio::test]
+    async fn subscribe_with_credits() {
+        let msg_store = kv::new_kvstore(kv::DbKind::MEMORY, "root".into()).unwrap();
+        let service = Arc::new(make_one_queue(
+            msg_store,
+            &"test_node".into(),
+            &"root".into(),
+            TopicOptions::default(),
+            None,
+        ));
+        for meta in ["r1", "r2", "r3"] {
+            let request = tonic::Request::new(EnqueueRequest {
+                topic: "test".into(),
+                payload: vec![1, 2, 3],
+                meta: meta.into(),
+                priority: 0,
+                deliver_after: 0,
+                ttl_ms: 0,
+                dedup_key: "".into(),
+                group_key: "".into(),
+            });
+            assert!(service.enqueue(request).is_ok());
+        }
+        let (grant_tx, grant_rx) = mpsc::channel(1);
+        let (item_tx, mut item_rx) = mpsc::channel(16);
+        let subscriber = service.clone();
+        let handle = tokio::task::spawn(async move {
+            let inbound = tokio_stream::wrappers::ReceiverStream::new(grant_rx);
+            subscriber.subscribe(5000, 2, inbound, item_tx).await
+        });
+        let first = item_rx.recv().await.unwrap().unwrap();
+        let second = item_rx.recv().await.unwrap().unwrap();
+        assert_eq!(first.meta, "r1");
+        assert_eq!(second.meta, "r2");
+        sleep(Duration::from_millis(200)).await;
+        assert!(item_rx.try_recv().is_err());
+        grant_tx
+            .send(Ok(SubscribeRequest {
+                credits: 1,
+                ..Default::default()
+            }))
+            .await
+            .unwrap();
+        let third = item_rx.recv().await.unwrap().unwrap();
+        assert_eq!(third.meta, "r3");
+        assert_eq!(service.get_stats().delayed_size, 3);
+        drop(grant_tx);
+        handle.await.unwrap();
+        service.stop().await;
+    }
+
+    #[tokio::test]
+    async fn dead_letter_after_max_deliveries() {
+        let msg_store = kv::new_kvstore(kv::DbKind::MEMORY, "dead".into()).unwrap();
+        let dead_service = Arc::new(make_one_queue(
+            msg_store,
+            &"test_node".into(),
+            &"dead".into(),
+            TopicOptions::default(),
+            None,
+        ));
+        let sink_service = dead_service.clone();
+        let sink: DeadLetterSink = Arc::new(move |request| {
+            sink_service
+                .enqueue(tonic::Request::new(request))
+                .map(|reply| reply.into_inner())
+        });
+        let msg_store = kv::new_kvstore(kv::DbKind::MEMORY, "root".into()).unwrap();
+        let options = TopicOptions {
+            max_deliveries: 2,
+            dead_letter_topic: "dead".into(),
+            ..Default::default()
+        };
+        let service = make_one_queue(
+            msg_store,
+            &"test_node".into(),
+            &"root".into(),
+            options,
+            Some(sink),
+        );
+        let r1 = tonic::Request::new(EnqueueRequest {
+            topic: "root".into(),
+            payload: vec![1, 2, 3],
+            meta: "r1".into(),
+            priority: 0,
+            deliver_after: 0,
+            ttl_ms: 0,
+            dedup_key: "".into(),
+            group_key: "".into(),
+        });
+        assert!(service.enqueue(r1).is_ok());
+        for _ in 0..2 {
+            let pops = service
+                .dequeue(tonic::Request::new(DequeueRequest {
+                    topic: "root".into(),
+                    count: 1,
+                    lease_duration: 50,
+                    wait_timeout_ms: 1000,
+                }))
+                .await
+                .unwrap();
+            assert_eq!(pops.get_ref().items[0].meta, "r1");
+        }
+        let pops = service
+            .dequeue(tonic::Request::new(DequeueRequest {
+                topic: "root".into(),
+                count: 1,
+                lease_duration: 50,
+                wait_timeout_ms: 300,
             }))
+            .await
             .unwrap();
         assert_eq!(pops.get_ref().items.len(), 0);
+        let pops = dead_service
+            .dequeue(tonic::Request::new(DequeueRequest {
+                topic: "dead".into(),
+                count: 1,
+                lease_duration: 0,
+                wait_timeout_ms: 0,
+            }))
+            .await
+            .unwrap();
+        assert_eq!(pops.get_ref().items[0].meta, "r1");
+        assert_eq!(pops.get_ref().items[0].payload, vec![1, 2, 3]);
+        service.stop().await;
+        dead_service.stop().await;
+    }
+
+    #[tokio::test]
+    async fn dead_letter_retries_are_capped() {
+        let sink: DeadLetterSink = Arc::new(|_| Err(Status::not_found("dead")));
+        let msg_store = kv::new_kvstore(kv::DbKind::MEMORY, "root".into()).unwrap();
+        let options = TopicOptions {
+            max_deliveries: 1,
+            dead_letter_topic: "dead".into(),
+            ..Default::default()
+        };
+        let service = make_one_queue(
+            msg_store,
+            &"test_node".into(),
+            &"root".into(),
+            options,
+            Some(sink),
+        );
+        let reply = service
+            .enqueue(tonic::Request::new(EnqueueRequest {
+                payload: vec![1],
+                ..Default::default()
+            }))
+            .unwrap();
+        let (message_ids, _) = parse_message_ids(&vec![reply.get_ref().message_id.clone()]);
+        let task = TaskItem {
+            message_id: message_ids[0].clone(),
+            deliveries: 1,
+            ..Default::default()
+        };
+        service.worker.fetch_tasks(1);
+        service.move_to_dead_letter(task.clone());
+        assert_eq!(service.get_stats().delayed_size, 1);
+        let state = service.state.read().unwrap();
+        assert!(state.index_store.get(&task.message_id).is_ok());
+        drop(state);
+        service.move_to_dead_letter(TaskItem {
+            deliveries: 1 + DEAD_LETTER_RETRIES,
+            ..task.clone()
+        });
+        let state = service.state.read().unwrap();
+        assert!(state.index_store.get(&task.message_id).is_err());
+        drop(state);
+        service.stop().await;
+    }
+
+    #[tokio::test]
+    async fn expire_skips_messages_put_back() {
+        let msg_store = kv::new_kvstore(kv::DbKind::MEMORY, "root".into()).unwrap();
+        let service = make_one_queue(
+            msg_store,
+            &"test_node".into(),
+            &"root".into(),
+            TopicOptions::default(),
+            None,
+        );
+        for _ in 0..2 {
+            service
+                .enqueue(tonic::Request::new(EnqueueRequest {
+                    payload: vec![1],
+                    deliver_after: 10000,
+                    ..Default::default()
+                }))
+                .unwrap();
+        }
+        let handler = expire_handler(
+            service.state.clone(),
+            "root".into(),
+            "".into(),
+            None,
+            service.worker.waiting_tasks(),
+        );
+        let message_id = 1u64.to_be_bytes().to_vec();
+        // still waiting, as after a nack raced the expiry
+        handler(vec![message_id.clone()]);
+        assert!(service
+            .state
+            .read()
+            .unwrap()
+            .index_store
+            .get(&message_id)
+            .is_ok());
+        service.worker.cancel_task(&message_id);
+        handler(vec![message_id.clone()]);
+        assert!(service
+            .state
+            .read()
+            .unwrap()
+            .index_store
+            .get(&message_id)
+            .is_err());
+        service.stop().await;
+    }
+
+    #[tokio::test]
+    async fn release_waits_for_handles() {
+        let msg_store = kv::new_kvstore(kv::DbKind::MEMORY, "root".into()).unwrap();
+        let service = Arc::new(make_one_queue(
+            msg_store,
+            &"test_node".into(),
+            &"root".into(),
+            TopicOptions::default(),
+            None,
+        ));
+        let releasing = service.releasing();
+        let held = service.clone();
+        let started = time::Instant::now();
+        tokio::spawn(async move {
+            sleep(Duration::from_millis(100)).await;
+            drop(held);
+        });
+        service.stop().await;
+        drop(service);
+        assert!(!releasing.wait(Duration::from_millis(20)).await);
+        assert!(releasing.wait(Duration::from_secs(5)).await);
+        assert!(started.elapsed() >= Duration::from_millis(80));
+    }
+
+    #[tokio::test]
+    async fn stop_ends_waiting_subscribers() {
+        let msg_store = kv::new_kvstore(kv::DbKind::MEMORY, "root".into()).unwrap();
+        let service = Arc::new(make_one_queue(
+            msg_store,
+            &"test_node".into(),
+            &"root".into(),
+            TopicOptions::default(),
+            None,
+        ));
+        for priority in 0..3 {
+            let r = tonic::Request::new(EnqueueRequest {
+                payload: vec![priority as u8],
+                priority,
+                ..Default::default()
+            });
+            service.enqueue(r).unwrap();
+        }
+        let releasing = service.releasing();
+        // one waits for credits, the other on a client that reads nothing
+        let mut outbounds = Vec::new();
+        for credits in [0, 3] {
+            let (tx, rx) = mpsc::channel(1);
+            outbounds.push(rx);
+            let svc = service.clone();
+            tokio::spawn(async move {
+                svc.subscribe(5000, credits, tokio_stream::pending(), tx)
+                    .await
+            });
+        }
+        sleep(Duration::from_millis(100)).await;
+        service.stop().await;
+        drop(service);
+        assert!(releasing.wait(Duration::from_secs(5)).await);
+        assert_eq!(outbounds[1].recv().await.unwrap().unwrap().payload, vec![0]);
+    }
+
+    #[tokio::test]
+    async fn extend_lease() {
+        let msg_store = kv::new_kvstore(kv::DbKind::MEMORY, "root".into()).unwrap();
+        let service = make_one_queue(
+            msg_store,
+            &"test_node".into(),
+            &"root".into(),
+            TopicOptions::default(),
+            None,
+        );
+        let r1 = tonic::Request::new(EnqueueRequest {
+            topic: "test".into(),
+            payload: vec![1, 2, 3],
+            meta: "r1".into(),
+            priority: 0,
+            deliver_after: 0,
+            ttl_ms: 0,
+            dedup_key: "".into(),
+            group_key: "".into(),
+        });
+        assert!(service.enqueue(r1).is_ok());
+        let dequeue_request = || {
+            tonic::Request::new(DequeueRequest {
+                topic: "test".into(),
+                count: 1,
+                lease_duration: 200,
+                wait_timeout_ms: 0,
+            })
+        };
+        let extend_request = |message_id: &String, deliveries: u32| {
+            tonic::Request::new(ExtendLeaseRequest {
+                topic: "test".into(),
+                message_id: message_id.clone(),
+                lease_duration: 1000,
+                deliveries,
+            })
+        };
+        let pops = service.dequeue(dequeue_request()).await.unwrap();
+        let item = &pops.get_ref().items[0];
+        assert_eq!(item.deliveries, 1);
+        assert!(service
+            .extend_lease(extend_request(&item.message_id, 2))
+            .is_err());
+        assert!(service
+            .extend_lease(extend_request(&item.message_id, 1))
+            .is_ok());
+        sleep(Duration::from_millis(500)).await;
+        let pops = service.dequeue(dequeue_request()).await.unwrap();
+        assert_eq!(pops.get_ref().items.len(), 0);
+        sleep(Duration::from_millis(600)).await;
+        let pops = service.dequeue(dequeue_request()).await.unwrap();
+        let item = &pops.get_ref().items[0];
+        assert_eq!(item.deliveries, 2);
+        let status = service
+            .extend_lease(extend_request(&item.message_id, 1))
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::FailedPrecondition);
+        // a delayed message is not leased, so it can not be pushed back
+        let reply = service
+            .enqueue(tonic::Request::new(EnqueueRequest {
+                payload: vec![1],
+                deliver_after: 10000,
+                ..Default::default()
+            }))
+            .unwrap();
+        let status = service
+            .extend_lease(extend_request(&reply.get_ref().message_id, 0))
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::FailedPrecondition);
+        service.stop().await;
+    }
+
+    #[tokio::test]
+    async fn enqueue_batch() {
+        let msg_store = kv::new_kvstore(kv::DbKind::MEMORY, "root".into()).unwrap();
+        let service = make_one_queue(
+            msg_store,
+            &"test_node".into(),
+            &"root".into(),
+            TopicOptions::default(),
+            None,
+        );
+        let items = vec![("r1", 1), ("r2", 0), ("r3", 2)]
+            .into_iter()
+            .map(|(meta, priority)| EnqueueRequest {
+                topic: "".into(),
+                payload: vec![1, 2, 3],
+                meta: meta.into(),
+                priority,
+                deliver_after: 0,
+                ttl_ms: 0,
+                dedup_key: "".into(),
+                group_key: "".into(),
+            })
+            .collect();
+        let reply = service
+            .enqueue_batch(tonic::Request::new(EnqueueBatchRequest {
+                topic: "test".into(),
+                items,
+            }))
+            .unwrap();
+        assert_eq!(reply.get_ref().message_ids, vec!["1", "2", "3"]);
+        let pops = service
+            .dequeue(tonic::Request::new(DequeueRequest {
+                topic: "test".into(),
+                count: 3,
+                lease_duration: 0,
+                wait_timeout_ms: 0,
+            }))
+            .await
+            .unwrap();
+        let metas: Vec<&str> = pops
+            .get_ref()
+            .items
+            .iter()
+            .map(|x| x.meta.as_str())
+            .collect();
+        assert_eq!(metas, vec!["r2", "r1", "r3"]);
+        service.stop().await;
+    }
+
+    #[tokio::test]
+    async fn ack_nack_batch() {
+        let msg_store = kv::new_kvstore(kv::DbKind::MEMORY, "root".into()).unwrap();
+        let service = make_one_queue(
+            msg_store,
+            &"test_node".into(),
+            &"root".into(),
+            TopicOptions::default(),
+            None,
+        );
+        for meta in ["r1", "r2", "r3", "r4"] {
+            let request = tonic::Request::new(EnqueueRequest {
+                topic: "test".into(),
+                payload: vec![1, 2, 3],
+                meta: meta.into(),
+                priority: 0,
+                deliver_after: 0,
+                ttl_ms: 0,
+                dedup_key: "".into(),
+                group_key: "".into(),
+            });
+            assert!(service.enqueue(request).is_ok());
+        }
+        let pops = service
+            .dequeue(tonic::Request::new(DequeueRequest {
+                topic: "test".into(),
+                count: 3,
+                lease_duration: 10000,
+                wait_timeout_ms: 0,
+            }))
+            .await
+            .unwrap();
+        let ids: Vec<String> = pops
+            .get_ref()
+            .items
+            .iter()
+            .map(|x| x.message_id.clone())
+            .collect();
+        let reply = service
+            .ack_batch(tonic::Request::new(AckBatchRequest {
+                topic: "test".into(),
+                message_ids: vec![ids[0].clone(), ids[1].clone(), "4".into(), "bad".into()],
+            }))
+            .unwrap();
+        let results = &reply.get_ref().results;
+        assert!(results[0].ok && results[1].ok);
+        assert_eq!(results[2].error, "no lease found");
+        assert_eq!(results[3].error, "invalid message id");
+        let reply = service
+            .nack_batch(tonic::Request::new(NackBatchRequest {
+                topic: "test".into(),
+                message_ids: vec![ids[2].clone(), ids[0].clone()],
+                meta: "again".into(),
+                deliver_after: 0,
+            }))
+            .unwrap();
+        let results = &reply.get_ref().results;
+        assert!(results[0].ok);
+        assert!(!results[1].ok);
+        let pops = service
+            .dequeue(tonic::Request::new(DequeueRequest {
+                topic: "test".into(),
+                count: 10,
+                lease_duration: 0,
+                wait_timeout_ms: 0,
+            }))
+            .await
+            .unwrap();
+        let metas: Vec<&str> = pops
+            .get_ref()
+            .items
+            .iter()
+            .map(|x| x.meta.as_str())
+            .collect();
+        assert_eq!(metas, vec!["r4", "again"]);
+        service.stop().await;
+    }
+
+    #[tokio::test]
+    async fn ttl_expiry() {
+        let msg_store = kv::new_kvstore(kv::DbKind::MEMORY, "root".into()).unwrap();
+        let options = TopicOptions {
+            default_ttl_ms: 100,
+            ..Default::default()
+        };
+        let service = make_one_queue(
+            msg_store,
+            &"test_node".into(),
+            &"root".into(),
+            options,
+            None,
+        );
+        let metas = vec![("r1", 0, 0), ("r2", 200, 0), ("r3", 0, 5000)];
+        for (meta, deliver_after, ttl_ms) in metas {
+            let request = tonic::Request::new(EnqueueRequest {
+                topic: "test".into(),
+                payload: vec![1, 2, 3],
+                meta: meta.into(),
+                priority: 0,
+                deliver_after,
+                ttl_ms,
+                dedup_key: "".into(),
+                group_key: "".into(),
+            });
+            assert!(service.enqueue(request).is_ok());
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(300)).await;
+        assert_eq!(service.get_stats().ready_size, 1);
+        let pops = service
+            .dequeue(tonic::Request::new(DequeueRequest {
+                topic: "test".into(),
+                count: 3,
+                lease_duration: 0,
+                wait_timeout_ms: 0,
+            }))
+            .await
+            .unwrap();
+        let metas: Vec<&str> = pops
+            .get_ref()
+            .items
+            .iter()
+            .map(|x| x.meta.as_str())
+            .collect();
+        assert_eq!(metas, vec!["r3"]);
+        assert_eq!(service.get_stats().expired, 2);
+        service.stop().await;
+    }
+
+    #[tokio::test]
+    async fn dedup_enqueue() {
+        let msg_store = kv::new_kvstore(kv::DbKind::MEMORY, "root".into()).unwrap();
+        let options = TopicOptions {
+            dedup_window_ms: 200,
+            ..Default::default()
+        };
+        let service = make_one_queue(
+            msg_store,
+            &"test_node".into(),
+            &"root".into(),
+            options,
+            None,
+        );
+        let make_request = |meta: &str, dedup_key: &str| EnqueueRequest {
+            topic: "test".into(),
+            payload: vec![1, 2, 3],
+            meta: meta.into(),
+            priority: 0,
+            deliver_after: 0,
+            ttl_ms: 0,
+            dedup_key: dedup_key.into(),
+            group_key: "".into(),
+        };
+        let first = service
+            .enqueue(tonic::Request::new(make_request("r1", "a")))
+            .unwrap();
+        let again = service
+            .enqueue(tonic::Request::new(make_request("r1", "a")))
+            .unwrap();
+        assert_eq!(first.get_ref().message_id, again.get_ref().message_id);
+        let reply = service
+            .enqueue_batch(tonic::Request::new(EnqueueBatchRequest {
+                topic: "test".into(),
+                items: vec![
+                    make_request("r1", "a"),
+                    make_request("r2", "b"),
+                    make_request("r3", ""),
+                    make_request("r2", "b"),
+                ],
+            }))
+            .unwrap();
+        let ids = &reply.get_ref().message_ids;
+        assert_eq!(ids[0], first.get_ref().message_id);
+        assert_eq!(ids[1], ids[3]);
+        assert_ne!(ids[1], ids[2]);
+        assert_eq!(service.get_stats().ready_size, 3);
+        tokio::time::sleep(std::time::Duration::from_millis(300)).await;
+        let later = service
+            .enqueue(tonic::Request::new(make_request("r4", "a")))
+            .unwrap();
+        assert_ne!(first.get_ref().message_id, later.get_ref().message_id);
+        service.stop().await;
+    }
+
+    #[tokio::test]
+    async fn group_fifo() {
+        let msg_store = kv::new_kvstore(kv::DbKind::MEMORY, "root".into()).unwrap();
+        let service = make_one_queue(
+            msg_store,
+            &"test_node".into(),
+            &"root".into(),
+            TopicOptions::default(),
+            None,
+        );
+        let items = vec![
+            ("a1", 0, "a"),
+            ("a2", 0, "a"),
+            ("b1", 1, "b"),
+            ("c1", 2, ""),
+        ];
+        for (meta, priority, group_key) in items {
+            let request = tonic::Request::new(EnqueueRequest {
+                topic: "test".into(),
+                payload: vec![1, 2, 3],
+                meta: meta.into(),
+                priority,
+                deliver_after: 0,
+                ttl_ms: 0,
+                dedup_key: "".into(),
+                group_key: group_key.into(),
+            });
+            assert!(service.enqueue(request).is_ok());
+        }
+        let dequeue = || {
+            service.dequeue(tonic::Request::new(DequeueRequest {
+                topic: "test".into(),
+                count: 10,
+                lease_duration: 10000,
+                wait_timeout_ms: 0,
+            }))
+        };
+        let pops = dequeue().await.unwrap();
+        let metas: Vec<&str> = pops
+            .get_ref()
+            .items
+            .iter()
+            .map(|x| x.meta.as_str())
+            .collect();
+        assert_eq!(metas, vec!["a1", "b1", "c1"]);
+        // three leased, a2 waits behind a1
+        assert_eq!(service.get_stats().delayed_size, 4);
+        let ids: Vec<String> = pops
+            .get_ref()
+            .items
+            .iter()
+            .map(|x| x.message_id.clone())
+            .collect();
+        assert_eq!(dequeue().await.unwrap().get_ref().items.len(), 0);
+        let ack = AckRequest {
+            topic: "test".into(),
+            message_id: ids[0].clone(),
+        };
+        assert!(service.ack(tonic::Request::new(ack)).is_ok());
+        let nack = NackRequest {
+            topic: "test".into(),
+            message_id: ids[1].clone(),
+            meta: "".into(),
+            deliver_after: 0,
+        };
+        assert!(service.nack(tonic::Request::new(nack)).is_ok());
+        let pops = dequeue().await.unwrap();
+        let metas: Vec<&str> = pops
+            .get_ref()
+            .items
+            .iter()
+            .map(|x| x.meta.as_str())
+            .collect();
+        assert_eq!(metas, vec!["a2", "b1"]);
+        service.stop().await;
+    }
+
+    #[tokio::test]
+    async fn recurring_schedule() {
+        let msg_store = kv::new_kvstore(kv::DbKind::MEMORY, "root".into()).unwrap();
+        let service = Arc::new(make_one_queue(
+            msg_store,
+            &"test_node".into(),
+            &"root".into(),
+            TopicOptions::default(),
+            None,
+        ));
+        service.start_schedules();
+        let template = EnqueueRequest {
+            meta: "tick".into(),
+            ..Default::default()
+        };
+        let bad = service.schedule_recurring(tonic::Request::new(ScheduleRecurringRequest {
+            topic: "root".into(),
+            cron: "not a cron".into(),
+            interval_ms: 0,
+            template: Some(template.clone()),
+        }));
+        assert!(bad.is_err());
+        let reply = service
+            .schedule_recurring(tonic::Request::new(ScheduleRecurringRequest {
+                topic: "root".into(),
+                cron: "".into(),
+                interval_ms: 100,
+                template: Some(template),
+            }))
+            .unwrap();
+        let schedule_id = reply.get_ref().schedule_id.clone();
+        let dequeue = || {
+            service.dequeue(tonic::Request::new(DequeueRequest {
+                topic: "root".into(),
+                count: 1,
+                lease_duration: 0,
+                wait_timeout_ms: 1000,
+            }))
+        };
+        for _ in 0..2 {
+            let pops = dequeue().await.unwrap();
+            assert_eq!(pops.get_ref().items[0].meta, "tick");
+        }
+        let list = service
+            .list_schedules(tonic::Request::new(ListSchedulesRequest {
+                topic: "root".into(),
+            }))
+            .unwrap();
+        assert_eq!(list.get_ref().schedules.len(), 1);
+        assert_eq!(list.get_ref().schedules[0].schedule_id, schedule_id);
+        service
+            .delete_schedule(tonic::Request::new(DeleteScheduleRequest {
+                topic: "root".into(),
+                schedule_id: schedule_id.clone(),
+            }))
+            .unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+        let _ = service.worker.fetch_tasks(10);
+        let pops = dequeue().await.unwrap();
+        assert_eq!(pops.get_ref().items.len(), 0);
+        // the deleted id is not handed out again, not even after a restart
+        let (schedules, last_id) = load_schedules(&service.state.read().unwrap().index_store);
+        assert!(schedules.is_empty());
+        assert_eq!(format!("{:}", last_id), schedule_id);
+        let reply = service
+            .schedule_recurring(tonic::Request::new(ScheduleRecurringRequest {
+                topic: "root".into(),
+                cron: "".into(),
+                interval_ms: 100000,
+                template: Some(EnqueueRequest::default()),
+            }))
+            .unwrap();
+        assert_ne!(reply.get_ref().schedule_id, schedule_id);
+        service.stop().await;
+    }
+
+    #[tokio::test]
+    async fn commit_payload_and_index() {
+        let tmp_dir = TempDir::new().unwrap();
+        let sub_dir: String = tmp_dir.path().to_str().unwrap().into();
+        let msg_store = kv::new_kvstore(kv::DbKind::SLED, sub_dir.clone()).unwrap();
+        let index_store = msg_store.open_space(INDEX_SPACE).unwrap();
+        let mut batch = WriteBatch::default();
+        batch.put(&msg_store, vec![1], vec![10]);
+        batch.put(&index_store, vec![1], vec![11]);
+        batch.delete(&msg_store, vec![2]);
+        msg_store.write(batch).unwrap();
+        assert_eq!(msg_store.get(&vec![1]).unwrap(), vec![10]);
+        assert_eq!(index_store.get(&vec![1]).unwrap(), vec![11]);
+        let msg_store = kv::new_kvstore(kv::DbKind::MEMORY, "root".into()).unwrap();
+        let service = make_one_queue(
+            msg_store,
+            &"test_node".into(),
+            &"root".into(),
+            TopicOptions::default(),
+            None,
+        );
+        for meta in ["m1", "m2"] {
+            let r = tonic::Request::new(EnqueueRequest {
+                meta: meta.into(),
+                ..Default::default()
+            });
+            service.enqueue(r).unwrap();
+        }
+        let pops = service
+            .dequeue(tonic::Request::new(DequeueRequest {
+                topic: "root".into(),
+                count: 2,
+                lease_duration: 10000,
+                wait_timeout_ms: 0,
+            }))
+            .await
+            .unwrap();
+        let ids: Vec<Vec<u8>> = pops
+            .get_ref()
+            .items
+            .iter()
+            .map(|item| utils::msgid_to_raw(&item.message_id))
+            .collect();
+        assert_eq!(ids.len(), 2);
+        {
+            let state = service.state.read().unwrap();
+            for message_id in &ids {
+                assert!(state.msg_store.get(message_id).is_ok());
+                assert!(state.index_store.get(message_id).is_ok());
+            }
+        }
+        for message_id in &ids {
+            let r = tonic::Request::new(AckRequest {
+                topic: "root".into(),
+                message_id: utils::msgid_to_str(message_id),
+            });
+            assert!(service.ack(r).is_ok());
+        }
+        let state = service.state.read().unwrap();
+        for message_id in &ids {
+            assert!(state.index_store.get(message_id).is_err());
+            // the key of the last seq_no is kept for restart, without its payload
+            match utils::msgid_to_u64(message_id) == state.seq_no.load(Ordering::SeqCst) {
+                true => assert_eq!(state.msg_store.get(message_id).unwrap(), KEPT_VALUE),
+                false => assert!(state.msg_store.get(message_id).is_err()),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn shared_column_families() {
+        let tmp_dir = TempDir::new().unwrap();
+        let db_dir: String = tmp_dir.path().to_str().unwrap().into();
+        let mut services = Vec::<PriorityQueueSvc>::new();
+        for topic in ["t1", "t2"] {
+            let sub_dir = format!("{}/{}", db_dir, topic);
+            let msg_store = kv::new_kvstore(kv::DbKind::ROCKSDBCF, sub_dir).unwrap();
+            let service = make_one_queue(
+                msg_store,
+                &"test_node".into(),
+                &topic.into(),
+                TopicOptions::default(),
+                None,
+            );
+            let r = tonic::Request::new(EnqueueRequest {
+                meta: topic.into(),
+                ..Default::default()
+            });
+            service.enqueue(r).unwrap();
+            services.push(service);
+        }
+        assert_eq!(kv::list_column_families(&db_dir), vec!["t1", "t2"]);
+        for (service, topic) in services.iter().zip(["t1", "t2"]) {
+            let pops = service.worker.fetch_tasks(10);
+            assert_eq!(pops.len(), 1);
+            let state = service.state.read().unwrap();
+            let value_buf = state.msg_store.get(&pops[0].message_id).unwrap();
+            assert_eq!(
+                EnqueueRequest::decode(value_buf.as_slice()).unwrap().meta,
+                topic
+            );
+        }
+        services.remove(0).stop().await;
+        kv::drop_kvstore(kv::DbKind::ROCKSDBCF, format!("{}/t1", db_dir)).unwrap();
+        assert_eq!(kv::list_column_families(&db_dir), vec!["t2"]);
+        services[0].stop().await;
+    }
+
+    #[test]
+    fn migrate_topic_between_engines() {
+        let tmp_dir = TempDir::new().unwrap();
+        let root_dir: String = tmp_dir.path().to_str().unwrap().into();
+        {
+            let meta_store =
+                kv::new_kvstore(kv::DbKind::ROCKSDB, format!("{}/_meta", root_dir)).unwrap();
+            let options = TopicOptions {
+                storage: "sled".into(),
+                ..Default::default()
+            };
+            let mut value_buf = Vec::<u8>::new();
+            options.encode(&mut value_buf).unwrap();
+            meta_store.set(&b"t1".to_vec(), value_buf).unwrap();
+            let store = kv::new_kvstore(kv::DbKind::SLED, format!("{}/t1", root_dir)).unwrap();
+            store.set(&vec![1], vec![10]).unwrap();
+            store.set(&vec![2], vec![20]).unwrap();
+            let index_store = store.open_space(INDEX_SPACE).unwrap();
+            index_store.set(&vec![2], vec![0]).unwrap();
+        }
+        let total =
+            crate::svc::multi_queue::migrate_topic(&root_dir, "t1", kv::DbKind::ROCKSDB).unwrap();
+        assert_eq!(total, 3);
+        assert!(
+            crate::svc::multi_queue::migrate_topic(&root_dir, "t1", kv::DbKind::ROCKSDB).is_err()
+        );
+        let store = kv::new_kvstore(kv::DbKind::ROCKSDB, format!("{}/t1", root_dir)).unwrap();
+        assert_eq!(store.get(&vec![1]).unwrap(), vec![10]);
+        assert_eq!(store.max_key().unwrap(), vec![2]);
+        let index_store = store.open_space(INDEX_SPACE).unwrap();
+        assert_eq!(index_store.get(&vec![2]).unwrap(), vec![0]);
+        let meta_store =
+            kv::new_kvstore(kv::DbKind::ROCKSDB, format!("{}/_meta", root_dir)).unwrap();
+        let value_buf = meta_store.get(&b"t1".to_vec()).unwrap();
+        let options = TopicOptions::decode(value_buf.as_slice()).unwrap();
+        assert_eq!(options.storage, "rocksdb");
+    }
+
+    #[tokio::test]
+    async fn backup_and_restore_topic() {
+        let tmp_dir = TempDir::new().unwrap();
+        let root_dir: String = tmp_dir.path().to_str().unwrap().into();
+        for (db_kind, snapshot_kind) in [
+            (kv::DbKind::SLED, kv::DbKind::SLED),
+            (kv::DbKind::ROCKSDB, kv::DbKind::ROCKSDB),
+            (kv::DbKind::MEMORY, kv::DbKind::ROCKSDB),
+        ] {
+            let topic_dir = format!("{}/{}", root_dir, db_kind);
+            let msg_store = kv::new_kvstore(db_kind, topic_dir).unwrap();
+            let service = make_one_queue(
+                msg_store,
+                &"test_node".into(),
+                &"root".into(),
+                TopicOptions::default(),
+                None,
+            );
+            for priority in 0..3 {
+                let r = tonic::Request::new(EnqueueRequest {
+                    payload: vec![priority as u8],
+                    priority,
+                    ..Default::default()
+                });
+                service.enqueue(r).unwrap();
+            }
+            let backup_dir = format!("{}/{}_backup", root_dir, db_kind);
+            assert_eq!(service.backup(&backup_dir).unwrap(), (snapshot_kind, 3));
+            service.stop().await;
+            let snapshot = kv::new_kvstore(snapshot_kind, backup_dir).unwrap();
+            let restored = kv::new_kvstore(kv::DbKind::MEMORY, "root".into()).unwrap();
+            assert_eq!(copy_topic(&snapshot, &restored).unwrap(), 6);
+            let service = make_one_queue(
+                restored,
+                &"test_node".into(),
+                &"root".into(),
+                TopicOptions::default(),
+                None,
+            );
+            let pops = service
+                .dequeue(tonic::Request::new(DequeueRequest {
+                    topic: "root".into(),
+                    count: 4,
+                    lease_duration: 0,
+                    wait_timeout_ms: 0,
+                }))
+                .await
+                .unwrap();
+            let payloads: Vec<Vec<u8>> = pops
+                .get_ref()
+                .items
+                .iter()
+                .map(|item| item.payload.clone())
+                .collect();
+            assert_eq!(payloads, vec![vec![0], vec![1], vec![2]]);
+            service.stop().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn check_and_repair_topic() {
+        let msg_store = kv::new_kvstore(kv::DbKind::MEMORY, "root".into()).unwrap();
+        let service = make_one_queue(
+            msg_store,
+            &"test_node".into(),
+            &"root".into(),
+            TopicOptions::default(),
+            None,
+        );
+        for priority in 0..4 {
+            let r = tonic::Request::new(EnqueueRequest {
+                payload: vec![priority as u8],
+                priority,
+                ..Default::default()
+            });
+            service.enqueue(r).unwrap();
+        }
+        {
+            let state = service.state.read().unwrap();
+            let message_id = |n: u64| n.to_be_bytes().to_vec();
+            state.index_store.remove(&message_id(1)).unwrap();
+            state.msg_store.remove(&message_id(2)).unwrap();
+            state.msg_store.set(&message_id(3), vec![0, 9]).unwrap();
+            state
+                .chunk_store
+                .set(&chunk_key(&message_id(99), 0), vec![1])
+                .unwrap();
+        }
+        let check = |repair: &str| {
+            service
+                .check(tonic::Request::new(CheckTopicRequest {
+                    topic: "root".into(),
+                    repair: repair.into(),
+                    quarantine_topic: "".into(),
+                }))
+                .unwrap()
+                .into_inner()
+        };
+        let reply = check("");
+        assert_eq!(reply.messages, 3);
+        assert_eq!(reply.orphan_payloads, vec!["1"]);
+        assert_eq!(reply.orphan_indexes, vec!["2"]);
+        assert_eq!(reply.broken_payloads, vec!["3"]);
+        assert_eq!(reply.orphan_chunks, 1);
+        assert_eq!(reply.repaired, 0);
+        // the unreadable payload is only unindexed
+        assert_eq!(check("reindex").repaired, 3);
+        let reply = check("");
+        assert!(reply.orphan_payloads.is_empty() && reply.orphan_indexes.is_empty());
+        assert_eq!(reply.broken_payloads, vec!["3"]);
+        assert_eq!(check("delete").repaired, 1);
+        let reply = check("");
+        assert_eq!(reply.messages, 2);
+        assert!(reply.broken_payloads.is_empty());
+        let pops = service
+            .dequeue(tonic::Request::new(DequeueRequest {
+                topic: "root".into(),
+                count: 4,
+                lease_duration: 0,
+                wait_timeout_ms: 0,
+            }))
+            .await
+            .unwrap();
+        let payloads: Vec<Vec<u8>> = pops
+            .get_ref()
+            .items
+            .iter()
+            .map(|item| item.payload.clone())
+            .collect();
+        assert_eq!(payloads, vec![vec![0], vec![3]]);
+        service.stop().await;
+    }
+
+    #[tokio::test]
+    async fn reindex_skips_kept_keys() {
+        let msg_store = kv::new_kvstore(kv::DbKind::MEMORY, "root".into()).unwrap();
+        let service = make_one_queue(
+            msg_store,
+            &"test_node".into(),
+            &"root".into(),
+            TopicOptions::default(),
+            None,
+        );
+        let dequeue = || {
+            service.dequeue(tonic::Request::new(DequeueRequest {
+                topic: "root".into(),
+                count: 4,
+                lease_duration: 5,
+                wait_timeout_ms: 0,
+            }))
+        };
+        for payload in [1, 2] {
+            service
+                .enqueue(tonic::Request::new(EnqueueRequest {
+                    payload: vec![payload],
+                    ..Default::default()
+                }))
+                .unwrap();
+            let pops = dequeue().await.unwrap().into_inner().items;
+            assert_eq!(pops.len(), 1);
+            assert_eq!(pops[0].payload, vec![payload]);
+            service
+                .ack(tonic::Request::new(AckRequest {
+                    topic: "root".into(),
+                    message_id: pops[0].message_id.clone(),
+                }))
+                .unwrap();
+        }
+        service
+            .enqueue(tonic::Request::new(EnqueueRequest {
+                payload: vec![3],
+                ..Default::default()
+            }))
+            .unwrap();
+        let reply = service
+            .check(tonic::Request::new(CheckTopicRequest {
+                topic: "root".into(),
+                repair: "reindex".into(),
+                quarantine_topic: "".into(),
+            }))
+            .unwrap()
+            .into_inner();
+        assert_eq!(reply.messages, 1);
+        assert!(reply.orphan_payloads.is_empty());
+        assert_eq!(reply.repaired, 0);
+        let payloads: Vec<Vec<u8>> = dequeue()
+            .await
+            .unwrap()
+            .into_inner()
+            .items
+            .into_iter()
+            .map(|item| item.payload)
+            .collect();
+        assert_eq!(payloads, vec![vec![3]]);
+        service.stop().await;
+    }
+
+    #[tokio::test]
+    async fn quarantine_repair() {
+        let quarantined = Arc::new(Mutex::new(Vec::<EnqueueRequest>::new()));
+        let sink_quarantined = quarantined.clone();
+        let sink: DeadLetterSink = Arc::new(move |request| {
+            sink_quarantined.lock().unwrap().push(request);
+            Ok(EnqueueReply::default())
+        });
+        let msg_store = kv::new_kvstore(kv::DbKind::MEMORY, "root".into()).unwrap();
+        let service = make_one_queue(
+            msg_store,
+            &"test_node".into(),
+            &"root".into(),
+            TopicOptions::default(),
+            Some(sink),
+        );
+        for priority in 0..3 {
+            let r = tonic::Request::new(EnqueueRequest {
+                payload: vec![priority as u8],
+                priority,
+                ..Default::default()
+            });
+            service.enqueue(r).unwrap();
+        }
+        {
+            let state = service.state.read().unwrap();
+            let message_id = |n: u64| n.to_be_bytes().to_vec();
+            state.index_store.remove(&message_id(1)).unwrap();
+            state.msg_store.set(&message_id(2), vec![0, 9]).unwrap();
+        }
+        let reply = service
+            .check(tonic::Request::new(CheckTopicRequest {
+                topic: "root".into(),
+                repair: "quarantine".into(),
+                quarantine_topic: "quarantine".into(),
+            }))
+            .unwrap()
+            .into_inner();
+        assert_eq!(reply.repaired, 2);
+        {
+            let quarantined = quarantined.lock().unwrap();
+            assert_eq!(quarantined.len(), 2);
+            assert!(quarantined.iter().all(|req| req.topic == "quarantine"));
+            // the readable orphan is forwarded as is, the unreadable one as raw bytes
+            assert_eq!(quarantined[0].payload, vec![0]);
+            assert_eq!(quarantined[1].payload, vec![0, 9]);
+            assert_eq!(quarantined[1].meta, "root:2");
+        }
+        let state = service.state.read().unwrap();
+        assert!(state.msg_store.get(&1u64.to_be_bytes().to_vec()).is_err());
+        assert!(state.msg_store.get(&2u64.to_be_bytes().to_vec()).is_err());
+        drop(state);
+        service.stop().await;
+
+        // without a sink there is nowhere to quarantine into
+        let msg_store = kv::new_kvstore(kv::DbKind::MEMORY, "root".into()).unwrap();
+        let service = make_one_queue(
+            msg_store,
+            &"test_node".into(),
+            &"root".into(),
+            TopicOptions::default(),
+            None,
+        );
+        let err = service
+            .check(tonic::Request::new(CheckTopicRequest {
+                topic: "root".into(),
+                repair: "quarantine".into(),
+                quarantine_topic: "quarantine".into(),
+            }))
+            .unwrap_err();
+        assert_eq!(err.code(), tonic::Code::FailedPrecondition);
+        service.stop().await;
+    }
+
+    #[tokio::test]
+    async fn delayed_message_wakes_idle_worker() {
+        let msg_store = kv::new_kvstore(kv::DbKind::MEMORY, "root".into()).unwrap();
+        let service = make_one_queue(
+            msg_store,
+            &"test_node".into(),
+            &"root".into(),
+            TopicOptions::default(),
+            None,
+        );
+        // the worker sleeps without a deadline until this one arrives
+        sleep(Duration::from_millis(20)).await;
+        let started = time::Instant::now();
+        let r = tonic::Request::new(EnqueueRequest {
+            payload: vec![1],
+            deliver_after: 50,
+            ..Default::default()
+        });
+        service.enqueue(r).unwrap();
+        let pops = service
+            .dequeue(tonic::Request::new(DequeueRequest {
+                topic: "root".into(),
+                count: 1,
+                lease_duration: 0,
+                wait_timeout_ms: 2000,
+            }))
+            .await
+            .unwrap();
+        assert_eq!(pops.get_ref().items.len(), 1);
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_millis(40) && elapsed < Duration::from_millis(1000));
+        service.stop().await;
+    }
+
+    #[tokio::test]
+    async fn leases_survive_restart() {
+        let msg_store = kv::new_kvstore(kv::DbKind::MEMORY, "root".into()).unwrap();
+        let service = make_one_queue(
+            msg_store,
+            &"test_node".into(),
+            &"root".into(),
+            TopicOptions::default(),
+            None,
+        );
+        for priority in 0..2 {
+            let r = tonic::Request::new(EnqueueRequest {
+                payload: vec![priority as u8],
+                priority,
+                ..Default::default()
+            });
+            service.enqueue(r).unwrap();
+        }
+        let dequeue_request = || {
+            tonic::Request::new(DequeueRequest {
+                topic: "root".into(),
+                count: 2,
+                lease_duration: 60000,
+                wait_timeout_ms: 0,
+            })
+        };
+        let pops = service
+            .dequeue(tonic::Request::new(DequeueRequest {
+                count: 1,
+                ..dequeue_request().into_inner()
+            }))
+            .await
+            .unwrap();
+        let leased = pops.get_ref().items[0].clone();
+        assert_eq!(leased.payload, vec![0]);
+        service.stop().await;
+        // a restarted topic rebuilds its worker from the same stores
+        let restarted = kv::new_kvstore(kv::DbKind::MEMORY, "root".into()).unwrap();
+        copy_topic(&service.state.read().unwrap().msg_store, &restarted).unwrap();
+        let service = make_one_queue(
+            restarted,
+            &"test_node".into(),
+            &"root".into(),
+            TopicOptions::default(),
+            None,
+        );
+        let pops = service.dequeue(dequeue_request()).await.unwrap();
+        assert_eq!(pops.get_ref().items.len(), 1);
+        assert_eq!(pops.get_ref().items[0].payload, vec![1]);
+        assert_eq!(service.get_stats().delayed_size, 2);
+        let extend = service.extend_lease(tonic::Request::new(ExtendLeaseRequest {
+            topic: "root".into(),
+            message_id: leased.message_id.clone(),
+            lease_duration: 60000,
+            deliveries: leased.deliveries,
+        }));
+        assert!(extend.is_ok());
+        service.stop().await;
+    }
+
+    // fails every write while failing is set
+    struct FailingKv {
+        inner: Box<dyn KvStore>,
+        failing: Arc<AtomicBool>,
+    }
+
+    impl KvStore for FailingKv {
+        fn get(&self, key: &Vec<u8>) -> Result<Vec<u8>, KvError> {
+            self.inner.get(key)
+        }
+        fn set(&self, key: &Vec<u8>, value: Vec<u8>) -> Result<(), KvError> {
+            self.inner.set(key, value)
+        }
+        fn write(&self, batch: WriteBatch) -> Result<(), KvError> {
+            if self.failing.load(Ordering::SeqCst) {
+                return Err(KvError::IoError("disk full".into()));
+            }
+            self.inner.write(batch)
+        }
+        fn iter(&self, range: KeyRange) -> Result<kv::KvIter<'_>, KvError> {
+            self.inner.iter(range)
+        }
+        fn remove(&self, key: &Vec<u8>) -> Result<(), KvError> {
+            self.inner.remove(key)
+        }
+        fn max_key(&self) -> Result<Vec<u8>, KvError> {
+            self.inner.max_key()
+        }
+        fn space(&self) -> &str {
+            self.inner.space()
+        }
+        fn open_space(&self, name: &str) -> Result<Box<dyn KvStore>, KvError> {
+            self.inner.open_space(name)
+        }
+        fn checkpoint(&self, dir: &str) -> Result<Option<DbKind>, KvError> {
+            self.inner.checkpoint(dir)
+        }
+    }
+
+    #[tokio::test]
+    async fn unsaved_leases_stay_ready() {
+        let failing = Arc::new(AtomicBool::new(false));
+        let msg_store = Box::new(FailingKv {
+            inner: kv::new_kvstore(kv::DbKind::MEMORY, "root".into()).unwrap(),
+            failing: failing.clone(),
+        });
+        let service = make_one_queue(
+            msg_store,
+            &"test_node".into(),
+            &"root".into(),
+            TopicOptions::default(),
+            None,
+        );
+        let r = tonic::Request::new(EnqueueRequest {
+            payload: vec![1],
+            ..Default::default()
+        });
+        service.enqueue(r).unwrap();
+        let dequeue_request = || {
+            tonic::Request::new(DequeueRequest {
+                topic: "root".into(),
+                count: 1,
+                lease_duration: 60000,
+                wait_timeout_ms: 0,
+            })
+        };
+        failing.store(true, Ordering::SeqCst);
+        let pops = service.dequeue(dequeue_request()).await.unwrap();
+        assert!(pops.get_ref().items.is_empty());
+        assert_eq!(service.get_stats().ready_size, 1);
+        failing.store(false, Ordering::SeqCst);
+        let pops = service.dequeue(dequeue_request()).await.unwrap();
+        assert_eq!(pops.get_ref().items[0].payload, vec![1]);
+        assert_eq!(pops.get_ref().items[0].deliveries, 1);
+        assert_eq!(service.get_stats().delayed_size, 1);
+        // an extension that is not saved leaves the lease as it was
+        let message_id = utils::msgid_to_raw(&pops.get_ref().items[0].message_id);
+        let saved_deadline = || {
+            let state = service.state.read().unwrap();
+            let index_buf = state.index_store.get(&message_id).unwrap();
+            InnerIndex::decode(index_buf.as_slice()).unwrap().timestamp
+        };
+        let leased_until = saved_deadline();
+        let extend_request = || {
+            tonic::Request::new(ExtendLeaseRequest {
+                topic: "root".into(),
+                message_id: pops.get_ref().items[0].message_id.clone(),
+                lease_duration: 120000,
+                deliveries: 1,
+            })
+        };
+        failing.store(true, Ordering::SeqCst);
+        let err = service.extend_lease(extend_request()).unwrap_err();
+        assert_eq!(err.code(), tonic::Code::Unknown);
+        assert_eq!(saved_deadline(), leased_until);
+        failing.store(false, Ordering::SeqCst);
+        service.extend_lease(extend_request()).unwrap();
+        assert!(saved_deadline() > leased_until);
+        service.stop().await;
+    }
+
+    #[tokio::test]
+    async fn aging_lifts_waiting_messages() {
+        for (aging_ms, first) in [(0, vec![0]), (10, vec![5])] {
+            let msg_store = kv::new_kvstore(kv::DbKind::MEMORY, "root".into()).unwrap();
+            let options = TopicOptions {
+                aging_ms,
+                ..Default::default()
+            };
+            let service = make_one_queue(
+                msg_store,
+                &"test_node".into(),
+                &"root".into(),
+                options,
+                None,
+            );
+            for priority in [5, 0] {
+                let r = tonic::Request::new(EnqueueRequest {
+                    payload: vec![priority as u8],
+                    priority,
+                    ..Default::default()
+                });
+                service.enqueue(r).unwrap();
+                // 100ms of waiting is worth 10 levels with aging
+                sleep(Duration::from_millis(100)).await;
+            }
+            let pops = service
+                .dequeue(tonic::Request::new(DequeueRequest {
+                    topic: "root".into(),
+                    count: 1,
+                    lease_duration: 0,
+                    wait_timeout_ms: 0,
+                }))
+                .await
+                .unwrap();
+            assert_eq!(pops.get_ref().items[0].payload, first);
+            service.stop().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn rate_limited_deliveries() {
+        let msg_store = kv::new_kvstore(kv::DbKind::MEMORY, "root".into()).unwrap();
+        let options = TopicOptions {
+            rate_limit: 10,
+            rate_burst: 2,
+            ..Default::default()
+        };
+        let service = make_one_queue(
+            msg_store,
+            &"test_node".into(),
+            &"root".into(),
+            options,
+            None,
+        );
+        for priority in 0..5 {
+            let r = tonic::Request::new(EnqueueRequest {
+                payload: vec![priority as u8],
+                priority,
+                ..Default::default()
+            });
+            service.enqueue(r).unwrap();
+        }
+        assert_eq!(service.worker.fetch_tasks(5).len(), 2);
+        assert_eq!(service.get_stats().throttled, 3);
+        // the same held messages are not counted again
+        assert!(service.worker.fetch_tasks(5).is_empty());
+        assert_eq!(service.get_stats().throttled, 3);
+        // a waiting dequeue gets the next token, one every 100ms
+        let started = time::Instant::now();
+        let pops = service
+            .dequeue(tonic::Request::new(DequeueRequest {
+                topic: "root".into(),
+                count: 1,
+                lease_duration: 0,
+                wait_timeout_ms: 2000,
+            }))
+            .await
+            .unwrap();
+        assert_eq!(pops.get_ref().items.len(), 1);
+        assert!(started.elapsed() >= Duration::from_millis(50));
+        service.set_rate_limit(0, 0);
+        assert_eq!(service.worker.fetch_tasks(5).len(), 2);
+        assert_eq!(service.get_stats().throttled, 0);
+        service.stop().await;
+    }
+
+    #[tokio::test]
+    async fn compressed_payloads() {
+        let msg_store = kv::new_kvstore(kv::DbKind::MEMORY, "root".into()).unwrap();
+        let options = TopicOptions {
+            compression: "zstd".into(),
+            compress_min_bytes: 100,
+            ..Default::default()
+        };
+        let mut service = make_one_queue(
+            msg_store,
+            &"test_node".into(),
+            &"root".into(),
+            options,
+            None,
+        );
+        let big = "{\"key\": \"value\"}".repeat(100).into_bytes();
+        for payload in [big.clone(), vec![1, 2, 3]] {
+            let r = tonic::Request::new(EnqueueRequest {
+                payload,
+                ..Default::default()
+            });
+            service.enqueue(r).unwrap();
+        }
+        // switching the codec keeps older values readable
+        service.compression = Compression::new("lz4", 100);
+        let r = tonic::Request::new(EnqueueRequest {
+            payload: big.clone(),
+            ..Default::default()
+        });
+        service.enqueue(r).unwrap();
+        {
+            let state = service.state.read().unwrap();
+            let stored = state.msg_store.get(&1u64.to_be_bytes().to_vec()).unwrap();
+            assert!(stored.len() < big.len() / 4);
+            let stored = state.msg_store.get(&2u64.to_be_bytes().to_vec()).unwrap();
+            assert!(EnqueueRequest::decode(stored.as_slice()).is_ok());
+        }
+        let pops = service
+            .dequeue(tonic::Request::new(DequeueRequest {
+                count: 3,
+                lease_duration: 60000,
+                ..Default::default()
+            }))
+            .await
+            .unwrap();
+        let payloads: Vec<Vec<u8>> = pops
+            .get_ref()
+            .items
+            .iter()
+            .map(|x| x.payload.clone())
+            .collect();
+        assert_eq!(payloads, vec![big.clone(), vec![1, 2, 3], big.clone()]);
+        let r = tonic::Request::new(NackRequest {
+            message_id: "1".into(),
+            ..Default::default()
+        });
+        service.nack(r).unwrap();
+        let pops = service
+            .dequeue(tonic::Request::new(DequeueRequest {
+                count: 1,
+                ..Default::default()
+            }))
+            .await
+            .unwrap();
+        assert_eq!(pops.get_ref().items[0].payload, big);
+        service.stop().await;
+    }
+
+    #[tokio::test]
+    async fn chunked_payloads() {
+        let msg_store = kv::new_kvstore(kv::DbKind::MEMORY, "root".into()).unwrap();
+        let options = TopicOptions {
+            chunk_bytes: 10,
+            ..Default::default()
+        };
+        let service = make_one_queue(
+            msg_store,
+            &"test_node".into(),
+            &"root".into(),
+            options,
+            None,
+        );
+        let big: Vec<u8> = (0..35).collect();
+        for _ in 0..2 {
+            let r = tonic::Request::new(EnqueueRequest {
+                payload: big.clone(),
+                ..Default::default()
+            });
+            service.enqueue(r).unwrap();
+        }
+        let pops = service
+            .dequeue(tonic::Request::new(DequeueRequest {
+                count: 1,
+                lease_duration: 60000,
+                ..Default::default()
+            }))
+            .await
+            .unwrap();
+        let item = &pops.get_ref().items[0];
+        assert!(item.payload.is_empty());
+        assert_eq!(item.payload_chunks, 4);
+        let (tx, mut rx) = mpsc::channel(1);
+        let fetch = service.fetch_payload(1, tx);
+        let collect = async {
+            let mut payload = Vec::<u8>::new();
+            while let Some(chunk) = rx.recv().await {
+                let chunk = chunk.unwrap();
+                assert_eq!(chunk.total, 4);
+                payload.extend_from_slice(&chunk.data);
+            }
+            payload
+        };
+        let (_, payload) = tokio::join!(fetch, collect);
+        assert_eq!(payload, big);
+        let r = tonic::Request::new(AckRequest {
+            message_id: "1".into(),
+            ..Default::default()
+        });
+        service.ack(r).unwrap();
+        {
+            let state = service.state.read().unwrap();
+            assert_eq!(state.chunk_count(&1u64.to_be_bytes().to_vec()), 0);
+            assert!(state
+                .load_chunk(&1u64.to_be_bytes().to_vec(), 0, true)
+                .is_err());
+        }
+        // only a leased message streams its payload
+        let (tx, mut rx) = mpsc::channel(1);
+        service.fetch_payload(2, tx).await;
+        assert_eq!(
+            rx.recv().await.unwrap().unwrap_err().code(),
+            tonic::Code::FailedPrecondition
+        );
+        // an unleased dequeue carries the whole payload
+        let pops = service
+            .dequeue(tonic::Request::new(DequeueRequest {
+                count: 1,
+                ..Default::default()
+            }))
+            .await
+            .unwrap();
+        let item = &pops.get_ref().items[0];
+        assert_eq!(item.payload, big);
+        assert_eq!(item.payload_chunks, 0);
+        service.stop().await;
+    }
+
+    #[tokio::test]
+    async fn compressed_chunks() {
+        let msg_store = kv::new_kvstore(kv::DbKind::MEMORY, "root".into()).unwrap();
+        let options = TopicOptions {
+            chunk_bytes: 64,
+            compression: "lz4".into(),
+            compress_min_bytes: 16,
+            ..Default::default()
+        };
+        let service = make_one_queue(
+            msg_store,
+            &"test_node".into(),
+            &"root".into(),
+            options,
+            None,
+        );
+        // compressible chunks and a short random-looking tail that stays raw
+        let mut big = vec![7u8; 256];
+        big.extend((0..20u8).map(|i| i.wrapping_mul(97) ^ 0x5a));
+        let r = tonic::Request::new(EnqueueRequest {
+            payload: big.clone(),
+            ..Default::default()
+        });
+        service.enqueue(r).unwrap();
+        {
+            let state = service.state.read().unwrap();
+            let message_id = 1u64.to_be_bytes().to_vec();
+            assert_eq!(state.chunk_meta(&message_id), (5, true));
+            let stored = state.chunk_store.get(&chunk_key(&message_id, 0)).unwrap();
+            assert!(stored.len() < 64);
+            assert_eq!(state.load_message(&message_id).unwrap().payload, big);
+        }
+        let pops = service
+            .dequeue(tonic::Request::new(DequeueRequest {
+                count: 1,
+                lease_duration: 60000,
+                ..Default::default()
+            }))
+            .await
+            .unwrap();
+        assert_eq!(pops.get_ref().items[0].payload_chunks, 5);
+        let (tx, mut rx) = mpsc::channel(1);
+        let fetch = service.fetch_payload(1, tx);
+        let collect = async {
+            let mut payload = Vec::<u8>::new();
+            while let Some(chunk) = rx.recv().await {
+                payload.extend_from_slice(&chunk.unwrap().data);
+            }
+            payload
+        };
+        let (_, payload) = tokio::join!(fetch, collect);
+        assert_eq!(payload, big);
+        service.stop().await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn group_commit_enqueues() {
+        let tmp_dir = TempDir::new().unwrap();
+        let db_dir: String = tmp_dir.path().to_str().unwrap().into();
+        let msg_store = kv::new_kvstore(kv::DbKind::SLED, db_dir).unwrap();
+        let options = TopicOptions {
+            durability: "group".into(),
+            group_commit_ms: 20,
+            ..Default::default()
+        };
+        let service = Arc::new(make_one_queue(
+            msg_store,
+            &"test_node".into(),
+            &"root".into(),
+            options,
+            None,
+        ));
+        let mut handles = Vec::new();
+        for i in 0..8 {
+            let service = service.clone();
+            handles.push(tokio::task::spawn_blocking(move || {
+                let r = tonic::Request::new(EnqueueRequest {
+                    meta: format!("{}", i),
+                    ..Default::default()
+                });
+                service.enqueue(r).unwrap()
+            }));
+        }
+        for handle in handles {
+            handle.await.unwrap();
+        }
+        let pops = service
+            .dequeue(tonic::Request::new(DequeueRequest {
+                count: 10,
+                ..Default::default()
+            }))
+            .await
+            .unwrap();
+        assert_eq!(pops.get_ref().items.len(), 8);
         service.stop().await;
     }
 }
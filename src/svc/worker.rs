@@ -2,9 +2,11 @@ use crate::svc::utils;
 use std::cmp::Reverse;
 use std::collections::BTreeMap;
 use std::collections::BinaryHeap;
+use std::collections::HashMap;
 use std::collections::HashSet;
 use std::collections::LinkedList;
 use std::ops::Bound::{Excluded, Included};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::sync::Mutex;
 use std::time::Duration;
@@ -12,16 +14,31 @@ use tokio::sync::Notify;
 use tokio::{task, time};
 use tracing::info;
 
-#[derive(Debug, Eq, Ord, PartialEq, PartialOrd, Default)]
+const WORKER_SHARDS: usize = 4;
+
+#[derive(Debug, Eq, Ord, PartialEq, PartialOrd, Default, Clone)]
 pub struct TaskItem {
     pub priority: i32,
     pub timestamp: u64,
     pub message_id: Vec<u8>,
+    pub deliveries: u32,
+    pub expire_at: u64,
+    pub group_key: String,
+}
+
+pub type ExpireHandler = Arc<dyn Fn(Vec<Vec<u8>>) + Send + Sync>;
+
+pub enum ExtendError {
+    Expired,
+    Redelivered,
+    Unsaved(String),
 }
 
 pub struct QueueStats {
     pub ready_size: u64,
     pub delayed_size: u64,
+    pub expired: u64,
+    pub throttled: u64,
 }
 
 impl TaskItem {
@@ -31,59 +48,469 @@ impl TaskItem {
             priority: self.priority,
             message_id: self.message_id.clone(),
             timestamp: now + milli_seconds as u64,
+            deliveries: self.deliveries,
+            expire_at: self.expire_at,
+            group_key: self.group_key.clone(),
         }
     }
 }
 
 #[derive(Default)]
+struct TokenBucket {
+    rate: u32, //per second, 0 means unlimited
+    burst: u32,
+    tokens: f64,
+    refilled_at: u64,
+}
+
+impl TokenBucket {
+    // tokens saved up under a limit carry over, capped at the new burst,
+    // a limit set on an unlimited bucket starts it full
+    fn set(&mut self, rate: u32, burst: u32, now: u64) {
+        let tokens = match self.rate {
+            0 => f64::MAX,
+            _ => {
+                self.available(now);
+                self.tokens
+            }
+        };
+        self.rate = rate;
+        self.burst = match burst {
+            0 => rate,
+            burst => burst,
+        };
+        self.tokens = tokens.min(self.burst as f64);
+        self.refilled_at = now;
+    }
+
+    // whole tokens that can be spent now
+    fn available(&mut self, now: u64) -> u32 {
+        if self.rate == 0 {
+            return u32::MAX;
+        }
+        let refill = now.saturating_sub(self.refilled_at) as f64 * self.rate as f64 / 1000.0;
+        self.tokens = (self.tokens + refill).min(self.burst as f64);
+        self.refilled_at = now;
+        self.tokens as u32
+    }
+
+    fn spend(&mut self, count: u32) {
+        if self.rate > 0 {
+            self.tokens -= count as f64;
+        }
+    }
+
+    // tokens spent up front but not used
+    fn refund(&mut self, count: u32) {
+        if self.rate > 0 {
+            self.tokens = (self.tokens + count as f64).min(self.burst as f64);
+        }
+    }
+
+    // ms until the next whole token
+    fn next_token_in(&self) -> u64 {
+        let missing = (1.0 - self.tokens).max(0.0);
+        (missing * 1000.0 / self.rate.max(1) as f64).ceil() as u64
+    }
+}
+
+#[derive(Default)]
+struct Limiter {
+    bucket: TokenBucket,
+    // ready tasks the last fetch held back, a gauge rather than a running count
+    throttled: u64,
+}
+
 pub struct Worker {
-    tasks: Arc<Mutex<TodoTasks>>,
+    shards: Arc<Shards>,
+    limiter: Mutex<Limiter>,
+    stop_flag: Arc<AtomicBool>,
     tk_handles: Vec<tokio::task::JoinHandle<()>>,
     notifier: Arc<Notify>,
+    expire_handler: Option<ExpireHandler>,
+}
+
+// tasks split by message id, so concurrent producers rarely meet on one lock
+struct Shards {
+    shards: Vec<Mutex<TodoTasks>>,
+    groups: Mutex<Groups>,
+    // group heads alive, finishing skips the groups lock while there are none
+    heads: AtomicUsize,
+    ready_notifier: Notify,
+    // wakes the worker loop before the deadline it sleeps until
+    timer: Notify,
 }
 
+// the ready queue order without the task, push numbers are unique across shards
+type ReadyKey = Reverse<(i64, u64, u64)>;
+
 #[derive(Default)]
 struct TodoTasks {
-    ready_queue: BinaryHeap<Reverse<TaskItem>>,
+    // ranked by rank_of, then by due time, then in the order they became ready
+    ready_queue: BinaryHeap<Reverse<(i64, u64, u64, TaskItem)>>,
     time_wheel: BTreeMap<u64, LinkedList<TaskItem>>,
-    in_wheel: HashSet<Vec<u8>>,
+    in_wheel: HashMap<Vec<u8>, TaskItem>,
     in_ready: HashSet<Vec<u8>>,
-    stop_flag: bool,
+    expire_wheel: BTreeMap<u64, Vec<Vec<u8>>>,
+    expired: Vec<Vec<u8>>,
+    expired_count: u64,
+    // deadline the worker loop sleeps until, 0 before it first runs
+    wake_at: u64,
+    wake: bool,
+    aging_ms: u32,
+    // shared by every shard, so ready order holds across them
+    pushes: Arc<AtomicU64>,
+}
+
+#[derive(Default)]
+struct Groups {
+    // one message per group is out at a time, the rest wait here in id order
+    pending: HashMap<String, BTreeMap<Vec<u8>, TaskItem>>,
+    heads: HashMap<Vec<u8>, String>,
+}
+
+impl Groups {
+    // None when the item has to wait behind the head of its group
+    fn admit(&mut self, item: TaskItem) -> Option<TaskItem> {
+        if self.heads.contains_key(&item.message_id) {
+            return Some(item);
+        }
+        match self.pending.get_mut(&item.group_key) {
+            Some(pending) => {
+                pending.insert(item.message_id.clone(), item);
+                None
+            }
+            None => {
+                self.pending.insert(item.group_key.clone(), BTreeMap::new());
+                self.heads
+                    .insert(item.message_id.clone(), item.group_key.clone());
+                Some(item)
+            }
+        }
+    }
+
+    // the next message of the group becomes its head once the head left the queue
+    fn finish(&mut self, message_id: &[u8]) -> Option<TaskItem> {
+        let group_key = self.heads.remove(message_id)?;
+        let pending = self.pending.get_mut(&group_key).unwrap();
+        let next_id = match pending.keys().next() {
+            Some(next_id) => next_id.clone(),
+            None => {
+                self.pending.remove(&group_key);
+                return None;
+            }
+        };
+        let item = pending.remove(&next_id).unwrap();
+        self.heads.insert(next_id, group_key);
+        Some(item)
+    }
+
+    fn forget(&mut self, message_id: &[u8]) {
+        for pending in self.pending.values_mut() {
+            pending.remove(message_id);
+        }
+    }
+}
+
+impl TodoTasks {
+    fn schedule(&mut self, item: TaskItem, now: u64) -> bool {
+        if item.timestamp <= now {
+            if self.in_ready.contains(&item.message_id) {
+                return false;
+            }
+            self.push_ready(item, now)
+        } else {
+            if self.in_wheel.contains_key(&item.message_id) {
+                return false;
+            }
+            self.in_ready.remove(&item.message_id);
+            self.in_wheel.insert(item.message_id.clone(), item.clone());
+            self.due(item.timestamp);
+            let ls = self.time_wheel.entry(item.timestamp).or_default();
+            ls.push_back(item);
+            false
+        }
+    }
+
+    fn push_ready(&mut self, item: TaskItem, now: u64) -> bool {
+        if item.expire_at > 0 {
+            if item.expire_at <= now {
+                self.expire(item.message_id, now);
+                return false;
+            }
+            self.due(item.expire_at);
+            self.expire_wheel
+                .entry(item.expire_at)
+                .or_default()
+                .push(item.message_id.clone());
+        }
+        self.in_ready.insert(item.message_id.clone());
+        let push_no = self.pushes.fetch_add(1, Ordering::SeqCst);
+        let rank = self.rank_of(&item);
+        self.ready_queue
+            .push(Reverse((rank, item.timestamp, push_no, item)));
+        true
+    }
+
+    // moves the due slots of the time wheel to the ready queue
+    fn advance(&mut self, now: u64) -> bool {
+        let window = (Excluded(0), Included(now));
+        let todo_list = self.time_wheel.range(window);
+        let mut near_slots = Vec::<u64>::with_capacity(10);
+        for (slot, _) in todo_list {
+            near_slots.push(slot.clone());
+        }
+        let mut moved = false;
+        for slot in near_slots {
+            let task_items = self.time_wheel.remove(&slot).unwrap();
+            for item in task_items {
+                // skip stale entries left behind by extend_task
+                let scheduled = self.in_wheel.get(&item.message_id);
+                if scheduled.map(|x| x.timestamp) == Some(item.timestamp) {
+                    self.in_wheel.remove(&item.message_id);
+                    moved |= self.push_ready(item, now);
+                }
+            }
+        }
+        self.expire_ready(now);
+        moved
+    }
+
+    fn expire_ready(&mut self, now: u64) {
+        let window = (Excluded(0), Included(now));
+        let mut near_slots = Vec::<u64>::with_capacity(10);
+        for (slot, _) in self.expire_wheel.range(window) {
+            near_slots.push(*slot);
+        }
+        for slot in near_slots {
+            for message_id in self.expire_wheel.remove(&slot).unwrap() {
+                // only the ones still waiting, leased ones are checked when they come back
+                if self.in_ready.remove(&message_id) {
+                    self.expire(message_id, now);
+                }
+            }
+        }
+    }
+
+    // the worker loop hands it to the expire handler and lets its group move on
+    fn expire(&mut self, message_id: Vec<u8>, now: u64) {
+        self.expired_count += 1;
+        self.expired.push(message_id);
+        self.due(now);
+    }
+
+    // pops the tops taken out of the ready set
+    // where the top ready task stands in the ready queue order
+    fn top_key(&mut self) -> Option<ReadyKey> {
+        self.drop_stale();
+        self.ready_queue
+            .peek()
+            .map(|Reverse((rank, timestamp, push_no, _))| Reverse((*rank, *timestamp, *push_no)))
+    }
+
+    fn drop_stale(&mut self) {
+        while let Some(Reverse((_, _, _, top))) = self.ready_queue.peek() {
+            if self.in_ready.contains(&top.message_id) {
+                return;
+            }
+            self.ready_queue.pop();
+        }
+    }
+
+    // an aged message gains a priority level per aging_ms since it was due, the
+    // gain grows equally for every waiting message, so the order fixed at push holds
+    fn rank_of(&self, item: &TaskItem) -> i64 {
+        match self.aging_ms {
+            0 => item.priority as i64,
+            // saturated, extreme priorities and aging_ms share the end of the range
+            aging_ms => (item.priority as i64)
+                .saturating_mul(aging_ms as i64)
+                .saturating_add(item.timestamp as i64),
+        }
+    }
+
+    // the worker loop has to run by the deadline
+    fn due(&mut self, deadline: u64) {
+        if self.wake_at > 0 && deadline < self.wake_at {
+            self.wake_at = deadline;
+            self.wake = true;
+        }
+    }
+
+    // the earliest slot of either wheel, u64::MAX when both are empty
+    fn next_deadline(&self) -> u64 {
+        let next_task = self.time_wheel.keys().next();
+        let next_expire = self.expire_wheel.keys().next();
+        match (next_task, next_expire) {
+            (Some(a), Some(b)) => *a.min(b),
+            (Some(a), None) | (None, Some(a)) => *a,
+            (None, None) => u64::MAX,
+        }
+    }
+}
+
+impl Shards {
+    // by the low bytes of the id, so sequential ids spread evenly
+    fn shard_of(&self, message_id: &[u8]) -> &Mutex<TodoTasks> {
+        &self.shards[self.shard_no(message_id)]
+    }
+
+    fn shard_no(&self, message_id: &[u8]) -> usize {
+        let mut dst = [0u8; 8];
+        let tail = &message_id[message_id.len().saturating_sub(8)..];
+        dst[8 - tail.len()..].copy_from_slice(tail);
+        (u64::from_be_bytes(dst) % self.shards.len() as u64) as usize
+    }
+
+    // positions of the ids in each shard, so a batch takes every shard lock once
+    fn by_shard(&self, message_ids: &[Vec<u8>]) -> Vec<(&Mutex<TodoTasks>, Vec<usize>)> {
+        let mut positions = vec![Vec::<usize>::new(); self.shards.len()];
+        for (i, message_id) in message_ids.iter().enumerate() {
+            positions[self.shard_no(message_id)].push(i);
+        }
+        self.shards
+            .iter()
+            .zip(positions)
+            .filter(|(_, positions)| !positions.is_empty())
+            .collect()
+    }
+
+    fn schedule(&self, item: TaskItem, now: u64) {
+        let mut tasks = self.shard_of(&item.message_id).lock().unwrap();
+        if tasks.schedule(item, now) {
+            self.ready_notifier.notify_waiters();
+        }
+        self.wake_timer(&mut tasks);
+    }
+
+    fn wake_timer(&self, tasks: &mut TodoTasks) {
+        if std::mem::take(&mut tasks.wake) {
+            self.timer.notify_one();
+        }
+    }
+
+    // let the next message of each group go, an expired one is expired in turn
+    fn finish(&self, message_ids: &[Vec<u8>], now: u64) {
+        if self.heads.load(Ordering::SeqCst) == 0 {
+            return;
+        }
+        let next_items: Vec<TaskItem> = {
+            let mut groups = self.groups.lock().unwrap();
+            let next_items = message_ids
+                .iter()
+                .filter_map(|message_id| groups.finish(message_id))
+                .collect();
+            self.heads.store(groups.heads.len(), Ordering::SeqCst);
+            next_items
+        };
+        for item in next_items {
+            if item.expire_at > 0 && item.expire_at <= now {
+                let mut tasks = self.shard_of(&item.message_id).lock().unwrap();
+                tasks.expire(item.message_id, now);
+                self.wake_timer(&mut tasks);
+                continue;
+            }
+            self.schedule(item, now);
+        }
+    }
+}
+
+// looks up whether a message waits in the worker, for the expire handler it owns
+#[derive(Clone)]
+pub struct WaitingTasks {
+    shards: Arc<Shards>,
+}
+
+impl WaitingTasks {
+    pub fn contains(&self, message_id: &[u8]) -> bool {
+        {
+            let tasks = self.shards.shard_of(message_id).lock().unwrap();
+            if tasks.in_wheel.contains_key(message_id) || tasks.in_ready.contains(message_id) {
+                return true;
+            }
+        }
+        let groups = self.shards.groups.lock().unwrap();
+        groups
+            .pending
+            .values()
+            .any(|pending| pending.contains_key(message_id))
+    }
 }
 
 impl Worker {
+    // 0 shards uses WORKER_SHARDS
+    pub fn new(shards: u32) -> Worker {
+        let shards = match shards {
+            0 => WORKER_SHARDS,
+            shards => shards as usize,
+        };
+        let pushes = Arc::new(AtomicU64::new(0));
+        let shards = (0..shards)
+            .map(|_| {
+                Mutex::new(TodoTasks {
+                    pushes: pushes.clone(),
+                    ..Default::default()
+                })
+            })
+            .collect();
+        Worker {
+            shards: Arc::new(Shards {
+                shards,
+                groups: Mutex::default(),
+                heads: AtomicUsize::new(0),
+                ready_notifier: Notify::new(),
+                timer: Notify::new(),
+            }),
+            limiter: Mutex::default(),
+            stop_flag: Arc::new(AtomicBool::new(false)),
+            tk_handles: Vec::new(),
+            notifier: Arc::new(Notify::new()),
+            expire_handler: None,
+        }
+    }
+
     pub fn start(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-        let tasks = self.tasks.clone();
+        let shards = self.shards.clone();
+        let stop_flag = self.stop_flag.clone();
         self.notifier = Arc::new(Notify::new());
         let notifier = self.notifier.clone();
+        let expire_handler = self.expire_handler.clone();
         let handler = task::spawn(async move {
-            let mut interval = time::interval(Duration::from_millis(5));
             info!("worker start");
             loop {
-                interval.tick().await;
-                let now = utils::timestamp();
-                let mut tasks = tasks.lock().unwrap();
-                if tasks.stop_flag {
+                if stop_flag.load(Ordering::SeqCst) {
                     break;
                 }
-                let window = (Excluded(0), Included(now));
-                let todo_list = tasks.time_wheel.range(window);
-                let mut near_slots = Vec::<u64>::with_capacity(10);
-                for (slot, _) in todo_list {
-                    near_slots.push(slot.clone());
+                let now = utils::timestamp();
+                let mut expired = Vec::<Vec<u8>>::new();
+                let mut wake_at = u64::MAX;
+                let mut moved = false;
+                for shard in shards.shards.iter() {
+                    let mut tasks = shard.lock().unwrap();
+                    moved |= tasks.advance(now);
+                    expired.append(&mut tasks.expired);
+                    tasks.wake_at = tasks.next_deadline();
+                    tasks.wake = false;
+                    wake_at = wake_at.min(tasks.wake_at);
+                }
+                if moved {
+                    shards.ready_notifier.notify_waiters();
                 }
-                for slot in near_slots {
-                    let task_items = tasks.time_wheel.remove(&slot).unwrap();
-                    for item in task_items {
-                        if tasks.in_wheel.contains(&item.message_id) {
-                            {
-                                tasks.in_wheel.remove(&item.message_id);
-                                tasks.in_ready.insert(item.message_id.clone());
-                            }
-                            tasks.ready_queue.push(Reverse(item));
-                        }
+                if !expired.is_empty() {
+                    shards.finish(&expired, now);
+                    // the handler reads and writes storage, so it stays off the loop
+                    if let Some(expire_handler) = expire_handler.clone() {
+                        task::spawn_blocking(move || expire_handler(expired));
                     }
                 }
+                // a wakeup sent before we wait is kept as a permit, so none is lost
+                let now = utils::timestamp();
+                if wake_at == u64::MAX {
+                    shards.timer.notified().await;
+                } else if wake_at > now {
+                    let wait = Duration::from_millis(wake_at - now);
+                    let _r = time::timeout(wait, shards.timer.notified()).await;
+                }
             }
             info!("worker stopped");
             notifier.notify_one();
@@ -92,72 +519,354 @@ impl Worker {
         Ok(())
     }
 
+    pub fn set_aging(&mut self, aging_ms: u32) {
+        for shard in self.shards.shards.iter() {
+            shard.lock().unwrap().aging_ms = aging_ms;
+        }
+    }
+
+    // 0 lifts the limit, a burst of 0 is one second of rate
+    pub fn set_rate_limit(&self, rate: u32, burst: u32) {
+        let now = utils::timestamp();
+        self.limiter.lock().unwrap().bucket.set(rate, burst, now);
+    }
+
+    pub fn waiting_tasks(&self) -> WaitingTasks {
+        WaitingTasks {
+            shards: self.shards.clone(),
+        }
+    }
+
+    pub fn set_expire_handler(&mut self, expire_handler: ExpireHandler) {
+        self.expire_handler = Some(expire_handler);
+    }
+
     pub fn add_task(&self, item: TaskItem) -> () {
         let now = utils::timestamp();
-        let mut tasks = self.tasks.lock().unwrap();
-        if item.timestamp <= now {
-            if tasks.in_ready.contains(&item.message_id) {
-                return;
-            }
-            tasks.in_ready.insert(item.message_id.clone());
-            tasks.ready_queue.push(Reverse(item));
-        } else {
-            if tasks.in_wheel.contains(&item.message_id) {
-                return;
+        let item = match item.group_key.is_empty() {
+            true => item,
+            false => {
+                let mut groups = self.shards.groups.lock().unwrap();
+                let admitted = groups.admit(item);
+                self.shards
+                    .heads
+                    .store(groups.heads.len(), Ordering::SeqCst);
+                match admitted {
+                    Some(item) => item,
+                    None => return,
+                }
             }
-            tasks.in_ready.remove(&item.message_id);
-            tasks.in_wheel.insert(item.message_id.clone());
-            let ls = tasks
-                .time_wheel
-                .entry(item.timestamp)
-                .or_insert(LinkedList::<TaskItem>::new());
-            ls.push_back(item);
-        }
+        };
+        self.shards.schedule(item, now);
     }
 
     pub fn fetch_tasks(&self, count: u32) -> Vec<TaskItem> {
-        let mut tasks = self.tasks.lock().unwrap();
-        let mut items = Vec::<TaskItem>::with_capacity(100);
-        let mut ct = 0;
-        while ct < count {
-            match tasks.ready_queue.pop() {
-                Some(Reverse(top)) => {
-                    if !tasks.in_ready.contains(&top.message_id) {
-                        continue;
-                    }
-                    tasks.in_ready.remove(&top.message_id);
-                    items.push(top);
-                    ct += 1;
-                }
+        self.take_tasks(count).0
+    }
+
+    // also the ms until the rate limit lets more go, when it held back ready tasks;
+    // one shard is locked at a time, so producers only ever wait on the one they push to
+    fn take_tasks(&self, count: u32) -> (Vec<TaskItem>, Option<u64>) {
+        let now = utils::timestamp();
+        // tokens are taken up front and the unused ones given back after
+        let allowed = {
+            let mut limiter = self.limiter.lock().unwrap();
+            let allowed = count.min(limiter.bucket.available(now));
+            limiter.bucket.spend(allowed);
+            allowed
+        };
+        let shards = &self.shards.shards;
+        let mut tops: Vec<Option<ReadyKey>> = shards
+            .iter()
+            .map(|shard| shard.lock().unwrap().top_key())
+            .collect();
+        let mut items = Vec::<TaskItem>::with_capacity(allowed.min(100) as usize);
+        while (items.len() as u32) < allowed {
+            // merge the shards, the best of their tops goes next
+            let best = match tops
+                .iter()
+                .enumerate()
+                .filter_map(|(i, top)| top.as_ref().map(|top| (i, top)))
+                .max_by(|a, b| a.1.cmp(b.1))
+            {
+                Some((i, _)) => i,
                 None => break,
+            };
+            let next_best = tops
+                .iter()
+                .enumerate()
+                .filter(|(i, _)| *i != best)
+                .filter_map(|(_, top)| top.clone())
+                .max();
+            let mut tasks = shards[best].lock().unwrap();
+            // the shard goes on until another one has the better top
+            while (items.len() as u32) < allowed {
+                match (tasks.top_key(), &next_best) {
+                    (Some(top), Some(next)) if top < *next => break,
+                    (Some(_), _) => {}
+                    (None, _) => break,
+                }
+                let Reverse((_, _, _, top)) = tasks.ready_queue.pop().unwrap();
+                tasks.in_ready.remove(&top.message_id);
+                if top.expire_at > 0 && top.expire_at <= now {
+                    // the worker loop is woken to hand it to the expire handler
+                    tasks.expire(top.message_id, now);
+                    continue;
+                }
+                items.push(top);
+            }
+            tops[best] = tasks.top_key();
+            self.shards.wake_timer(&mut tasks);
+        }
+        let ct = items.len() as u32;
+        let mut ready = 0;
+        if ct < count && ct == allowed {
+            for shard in shards.iter() {
+                ready += shard.lock().unwrap().in_ready.len();
+            }
+        }
+        let mut limiter = self.limiter.lock().unwrap();
+        limiter.bucket.refund(allowed - ct);
+        limiter.throttled = 0;
+        let mut throttled_for = None;
+        if ready > 0 {
+            let held = (count - ct) as u64;
+            limiter.throttled = held.min(ready as u64);
+            throttled_for = Some(limiter.bucket.next_token_in());
+        }
+        (items, throttled_for)
+    }
+
+    pub async fn wait_tasks(&self, count: u32, wait_timeout: Duration) -> Vec<TaskItem> {
+        let deadline = time::Instant::now() + wait_timeout;
+        loop {
+            // register interest before fetching, so a wakeup between the two is not lost
+            let notified = self.shards.ready_notifier.notified();
+            let (items, throttled_for) = self.take_tasks(count);
+            if !items.is_empty() {
+                return items;
+            }
+            // nothing announces refilled tokens, so a throttled wait polls for them
+            let wake_at = match throttled_for {
+                Some(wait) => deadline.min(time::Instant::now() + Duration::from_millis(wait)),
+                None => deadline,
+            };
+            if time::timeout_at(wake_at, notified).await.is_err() && wake_at == deadline {
+                return items;
             }
         }
-        items
     }
 
     pub fn cancel_task(&self, message_id: &Vec<u8>) -> bool {
-        let mut tasks = self.tasks.lock().unwrap();
-        if !tasks.in_wheel.contains(message_id) {
-            false
-        } else {
-            tasks.in_wheel.remove(message_id)
+        let mut tasks = self.shards.shard_of(message_id).lock().unwrap();
+        tasks.in_wheel.remove(message_id).is_some()
+    }
+
+    pub fn cancel_tasks(&self, message_ids: &[Vec<u8>]) -> Vec<bool> {
+        let mut canceled = vec![false; message_ids.len()];
+        for (shard, positions) in self.shards.by_shard(message_ids) {
+            let mut tasks = shard.lock().unwrap();
+            for i in positions {
+                canceled[i] = tasks.in_wheel.remove(&message_ids[i]).is_some();
+            }
+        }
+        canceled
+    }
+
+    pub fn finish_task(&self, message_id: &Vec<u8>) {
+        self.finish_tasks(std::slice::from_ref(message_id));
+    }
+
+    pub fn finish_tasks(&self, message_ids: &[Vec<u8>]) {
+        self.shards.finish(message_ids, utils::timestamp());
+    }
+
+    // forgets messages wherever they wait, their groups move on
+    pub fn drop_tasks(&self, message_ids: &[Vec<u8>]) {
+        for (shard, positions) in self.shards.by_shard(message_ids) {
+            let mut tasks = shard.lock().unwrap();
+            for i in positions {
+                tasks.in_wheel.remove(&message_ids[i]);
+                tasks.in_ready.remove(&message_ids[i]);
+            }
+        }
+        {
+            let mut groups = self.shards.groups.lock().unwrap();
+            for message_id in message_ids {
+                groups.forget(message_id);
+            }
+        }
+        self.finish_tasks(message_ids);
+    }
+
+    // save runs under the shard lock, so an ack can not remove the message between the
+    // check and the saved lease; the lease is unchanged when save fails
+    pub fn extend_task(
+        &self,
+        message_id: &Vec<u8>,
+        deliveries: u32,
+        timestamp: u64,
+        save: impl FnOnce(&TaskItem) -> Result<(), String>,
+    ) -> Result<TaskItem, ExtendError> {
+        let mut tasks = self.shards.shard_of(message_id).lock().unwrap();
+        let item = match tasks.in_wheel.get(message_id) {
+            // a delayed message never handed out has no lease to extend
+            Some(scheduled) if scheduled.deliveries == 0 => return Err(ExtendError::Expired),
+            Some(scheduled) if deliveries > 0 && scheduled.deliveries != deliveries => {
+                return Err(ExtendError::Redelivered);
+            }
+            Some(scheduled) if scheduled.timestamp >= timestamp => {
+                return Ok(scheduled.clone());
+            }
+            Some(scheduled) => TaskItem {
+                timestamp,
+                ..scheduled.clone()
+            },
+            None => return Err(ExtendError::Expired),
+        };
+        if let Err(err) = save(&item) {
+            return Err(ExtendError::Unsaved(err));
+        }
+        // the old slot is earlier, so the worker loop is awake to see the new one
+        tasks.in_wheel.insert(message_id.clone(), item.clone());
+        let ls = tasks.time_wheel.entry(timestamp).or_default();
+        ls.push_back(item.clone());
+        Ok(item)
+    }
+
+    // handed out and not yet acked, nacked back or expired
+    pub fn is_leased(&self, message_id: &Vec<u8>) -> bool {
+        let tasks = self.shards.shard_of(message_id).lock().unwrap();
+        match tasks.in_wheel.get(message_id) {
+            Some(scheduled) => scheduled.deliveries > 0,
+            None => false,
         }
     }
 
     pub fn stats(&self) -> QueueStats {
-        let tasks = self.tasks.lock().unwrap();
-        QueueStats {
-            ready_size: tasks.ready_queue.len() as u64,
-            delayed_size: tasks.in_wheel.len() as u64,
+        let mut stats = QueueStats {
+            ready_size: 0,
+            delayed_size: 0,
+            expired: 0,
+            throttled: self.limiter.lock().unwrap().throttled,
+        };
+        for shard in self.shards.shards.iter() {
+            let tasks = shard.lock().unwrap();
+            // expired and dropped ids leave their heap entries behind until they surface
+            stats.ready_size += tasks.in_ready.len() as u64;
+            stats.delayed_size += tasks.in_wheel.len() as u64;
+            stats.expired += tasks.expired_count;
         }
+        // parked behind the head of their group
+        let groups = self.shards.groups.lock().unwrap();
+        for pending in groups.pending.values() {
+            stats.delayed_size += pending.len() as u64;
+        }
+        stats
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.stop_flag.load(Ordering::SeqCst)
     }
 
     pub async fn stop(&self) -> bool {
-        {
-            let mut tasks = self.tasks.lock().unwrap();
-            tasks.stop_flag = true;
-        }
+        self.stop_flag.store(true, Ordering::SeqCst);
+        self.shards.ready_notifier.notify_waiters();
+        self.shards.timer.notify_one();
         self.notifier.notified().await;
         return true;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rank_saturates() {
+        let tasks = TodoTasks {
+            aging_ms: u32::MAX,
+            ..Default::default()
+        };
+        let item = |priority| TaskItem {
+            priority,
+            timestamp: utils::timestamp(),
+            ..Default::default()
+        };
+        assert_eq!(tasks.rank_of(&item(i32::MAX)), i64::MAX);
+        assert!(tasks.rank_of(&item(i32::MIN)) < tasks.rank_of(&item(0)));
+        assert!(tasks.rank_of(&item(0)) < tasks.rank_of(&item(1)));
+    }
+
+    #[test]
+    fn rate_change_keeps_tokens() {
+        let mut bucket = TokenBucket::default();
+        bucket.set(10, 10, 0);
+        assert_eq!(bucket.available(0), 10);
+        bucket.spend(10);
+        // a larger burst does not hand out a fresh one
+        bucket.set(10, 20, 0);
+        assert_eq!(bucket.available(0), 0);
+        // refilled at the old rate, then capped at the new burst
+        bucket.set(5, 2, 1000);
+        assert_eq!(bucket.available(1000), 2);
+        bucket.set(0, 0, 1000);
+        assert_eq!(bucket.available(1000), u32::MAX);
+        bucket.set(5, 3, 1000);
+        assert_eq!(bucket.available(1000), 3);
+    }
+
+    #[tokio::test]
+    async fn sharded_fetch_keeps_priority_order() {
+        let mut worker = Worker::new(4);
+        let _r = worker.start();
+        for i in 0..20u64 {
+            worker.add_task(TaskItem {
+                priority: (i * 7 % 20) as i32,
+                message_id: i.to_be_bytes().to_vec(),
+                ..Default::default()
+            });
+        }
+        let priorities: Vec<i32> = worker.fetch_tasks(5).iter().map(|x| x.priority).collect();
+        assert_eq!(priorities, vec![0, 1, 2, 3, 4]);
+        let priorities: Vec<i32> = worker.fetch_tasks(20).iter().map(|x| x.priority).collect();
+        assert_eq!(priorities, (5..20).collect::<Vec<i32>>());
+        // a group spans shards, its next message goes once the head is finished
+        for i in 20..23u64 {
+            worker.add_task(TaskItem {
+                message_id: i.to_be_bytes().to_vec(),
+                group_key: "g".into(),
+                ..Default::default()
+            });
+        }
+        let items = worker.fetch_tasks(3);
+        assert_eq!(items.len(), 1);
+        worker.finish_task(&items[0].message_id);
+        let items = worker.fetch_tasks(3);
+        assert_eq!(items[0].message_id, 21u64.to_be_bytes().to_vec());
+        worker.stop().await;
+    }
+
+    #[tokio::test]
+    async fn batch_cancel_across_shards() {
+        let mut worker = Worker::new(4);
+        let _r = worker.start();
+        let later = utils::timestamp() + 60000;
+        for i in 0..6u64 {
+            worker.add_task(TaskItem {
+                timestamp: later,
+                message_id: i.to_be_bytes().to_vec(),
+                ..Default::default()
+            });
+        }
+        let message_ids: Vec<Vec<u8>> = [5u64, 0, 3, 0, 9, 2]
+            .iter()
+            .map(|i| i.to_be_bytes().to_vec())
+            .collect();
+        let canceled = worker.cancel_tasks(&message_ids);
+        assert_eq!(canceled, vec![true, true, true, false, false, true]);
+        let left = worker.cancel_tasks(&[1u64.to_be_bytes().to_vec(), 4u64.to_be_bytes().to_vec()]);
+        assert_eq!(left, vec![true, true]);
+        worker.stop().await;
+    }
+}